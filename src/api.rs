@@ -0,0 +1,220 @@
+use hyper::{Method, StatusCode, Error};
+use hyper::header::{ContentType, ContentLength};
+use hyper::server::{Request, Response};
+use futures::{Future, Stream};
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+use serde_json;
+
+use graph::*;
+use graph::Response as GraphResponse;
+
+use std::collections::BTreeMap;
+use std::sync::Arc;
+
+/// Prefix of the REST routes on the HTTP port.
+pub static API_PATH: &str = "/api/graphs";
+
+type ResponseFuture = Box<dyn Future<Item=Response, Error=Error>>;
+type GraphResult<T> = Result<T, PossibleErr>;
+
+/// What a request asks of the store.
+enum Call{
+    List,
+    Get(GraphId),
+    Put(GraphId, GraphData),
+    // commands for a graph that has to exist already
+    Edit(GraphId, Vec<Command>)
+}
+
+/// What the store answered.
+enum Outcome{
+    Graphs(GraphList),
+    Graph(GraphData),
+    // whether the graph was created, and the Response to each command in order
+    Replies(bool, Vec<GraphResponse>),
+    Missing(GraphId)
+}
+
+#[derive(Deserialize)]
+struct Link{
+    source: PortId,
+    target: PortId
+}
+
+#[derive(Serialize)]
+struct ApiError{
+    error: String
+}
+
+type Answer = (StatusCode, String);
+
+fn json<T: Serialize>(status: StatusCode, value: &T) -> Answer{
+    match serde_json::to_string(value){
+        Ok(body) => (status, body),
+        Err(e)   => error(StatusCode::InternalServerError, format!("JSON encoding failed {:?}", e))
+    }
+}
+
+fn error(status: StatusCode, error: String) -> Answer{
+    json(status, &ApiError{ error })
+}
+
+fn body_json<T: DeserializeOwned>(body: &[u8]) -> Result<T, Answer>{
+    serde_json::from_slice(body)
+        .map_err(|e| error(StatusCode::BadRequest, format!("Invalid body: {}", e)))
+}
+
+// `path` is what follows API_PATH
+fn parse(method: &Method, path: &str, body: &[u8]) -> Result<Call, Answer>{
+    let segments: Vec<&str> = path.split('/').filter(|s| !s.is_empty()).collect();
+    let not_found   = || error(StatusCode::NotFound, format!("No route for {}{}", API_PATH, path));
+    let not_allowed = || error(StatusCode::MethodNotAllowed,
+                               format!("{} isn't allowed on {}{}", method, API_PATH, path));
+    let id: GraphId = match segments.first(){
+        None if *method == Method::Get => return Ok(Call::List),
+        None     => return Err(not_allowed()),
+        Some(id) => id.parse().map_err(|_| not_found())?
+    };
+    let rest = match segments.len(){
+        1 => None,
+        2 => Some(segments[1]),
+        _ => return Err(not_found())
+    };
+    match (rest, method.clone()){
+        (None, Method::Get) => Ok(Call::Get(id)),
+        (None, Method::Put) => Ok(Call::Put(id, body_json(body)?)),
+        (Some("links"), Method::Post) => {
+            let Link{ source, target } = body_json(body)?;
+            Ok(Call::Edit(id, vec![Command::AddLink{ source, target }]))
+        },
+        (Some("links"), Method::Delete) => {
+            let Link{ source, target } = body_json(body)?;
+            Ok(Call::Edit(id, vec![Command::DelLink{ source, target }]))
+        },
+        (Some("data"), Method::Patch) => {
+            let data: BTreeMap<DataId, DataValue> = body_json(body)?;
+            Ok(Call::Edit(id, data.into_iter()
+                                  .map(|(id, value)| Command::SetData{ id, value })
+                                  .collect()))
+        },
+        (None, _) | (Some("links"), _) | (Some("data"), _) => Err(not_allowed()),
+        _ => Err(not_found())
+    }
+}
+
+fn answer(outcome: Outcome) -> Answer{
+    match outcome{
+        Outcome::Graphs(graphs) => json(StatusCode::Ok, &graphs),
+        Outcome::Graph(graph)   => json(StatusCode::Ok, &graph),
+        Outcome::Replies(created, responses) => {
+            let status =
+                if responses.iter().any(|r| matches!(*r, GraphResponse::Err{..})){
+                    StatusCode::UnprocessableEntity
+                }
+                else if created{
+                    StatusCode::Created
+                }
+                else{
+                    StatusCode::Ok
+                };
+            json(status, &responses)
+        },
+        Outcome::Missing(id) => error(StatusCode::NotFound, format!("GraphId {} does not exist", id))
+    }
+}
+
+// edits from the API are treated like a backend's, so its SetData goes to frontends only
+fn edit(store: &GraphStore, id: GraphId, command: &Command) -> GraphResult<GraphResponse>{
+    let response = match *command{
+        Command::SetData{ id: ref data_id, ref value } => {
+            store.set_data(id, data_id, value, ClientType::Frontend)?;
+            GraphResponse::Ok
+        },
+        _ => store.apply(id, command, ClientType::Both)?
+    };
+    Ok(response)
+}
+
+fn put(store: &GraphStore, id: GraphId, graph: GraphData) -> GraphResult<Outcome>{
+    let errors = graph.check_links();
+    if !errors.is_empty(){
+        let error = GraphResponse::Error(DataValue::List(
+            errors.into_iter().map(DataValue::from).collect()));
+        return Ok(Outcome::Replies(false, vec![error]));
+    }
+    let created = !store.contains_key(id);
+    if created{
+        store.empty_at(id);
+    }
+    let graph = Arc::new(graph);
+    store.set_graph(id, graph, ClientType::Frontend)?;
+    store.evaluate(id, None)?;
+    Ok(Outcome::Replies(created, vec![GraphResponse::Ok]))
+}
+
+fn carry_out(store: &GraphStore, call: Call) -> GraphResult<Outcome>{
+    let missing = match call{
+        Call::Get(id) | Call::Edit(id, _) if !store.contains_key(id) => Some(id),
+        _ => None
+    };
+    if let Some(id) = missing{
+        return Ok(Outcome::Missing(id));
+    }
+    match call{
+        Call::List    => Ok(Outcome::Graphs(store.list())),
+        Call::Get(id) => Ok(Outcome::Graph(store.get(id)?.data.read().unwrap().clone())),
+        Call::Put(id, graph) => put(store, id, graph),
+        Call::Edit(id, commands) => {
+            let mut replies = Vec::with_capacity(commands.len());
+            for command in commands{
+                replies.push(edit(store, id, &command)?);
+            }
+            store.evaluate(id, None)?;
+            Ok(Outcome::Replies(false, replies))
+        }
+    }
+}
+
+/// Answers the REST routes under API_PATH from the graphs in a GraphStore:
+///
+/// * `GET /api/graphs` lists the graphs
+/// * `GET /api/graphs/{id}` returns a graph
+/// * `PUT /api/graphs/{id}` replaces or creates a graph
+/// * `POST` and `DELETE /api/graphs/{id}/links` add and remove a `{"source", "target"}` link
+/// * `PATCH /api/graphs/{id}/data` sets each value in an object of data
+///
+/// Edits answer with the Response to each command and are broadcast like a backend's.
+#[derive(Clone)]
+pub struct Api{
+    store: GraphStore
+}
+
+impl Api{
+    pub fn new(store: GraphStore) -> Api{
+        Api{ store }
+    }
+
+    pub fn call(&self, req: Request) -> ResponseFuture{
+        let method  = req.method().clone();
+        let path    = String::from(&req.path()[API_PATH.len()..]);
+        #[allow(deprecated)] // hyper 0.11 has nothing to replace it with yet
+        let reqaddr = req.remote_addr().map(|a| a.to_string()).unwrap_or("0.0.0.0:0".into());
+        let store   = self.store.clone();
+        Box::new(req.body().concat2().map(move |body|{
+            let (status, body) = match parse(&method, &path, &body){
+                Ok(call) => match carry_out(&store, call){
+                    Ok(outcome) => answer(outcome),
+                    Err(e)      => error(StatusCode::InternalServerError, format!("{}", e))
+                },
+                Err(answer) => answer
+            };
+            info!("{:>20} - {} - {} {}{}", reqaddr, status.as_u16(), method, API_PATH, path);
+            Response::new()
+                .with_status(status)
+                .with_header(ContentType::json())
+                .with_header(ContentLength(body.len() as u64))
+                .with_body(body)
+        }))
+    }
+}
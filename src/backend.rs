@@ -0,0 +1,384 @@
+use serde::{Deserialize, Deserializer};
+use serde::de::DeserializeOwned;
+use serde_json;
+use rmp_serde;
+use ws;
+use ws::{Handler, Handshake, Request, Message, CloseCode, Sender as WsSender};
+use ws::{Error as WsError, ErrorKind as WsErrorKind, Result as WsResult};
+use url::Url;
+
+use graph::*;
+use wire;
+
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{channel, Sender, Receiver};
+use std::thread;
+use std::thread::{JoinHandle};
+
+/// How a backend attaches to the server.
+#[derive(Copy, Clone, Debug, Default)]
+pub struct AttachOptions{
+    /// Graph to attach to, a new graph is created if it's None or doesn't exist. `Client::graph`
+    /// tells which one it was.
+    pub graph: Option<GraphId>,
+    pub codec: Codec,
+    /// Asks the server to send Ok responses as well as errors and warnings.
+    pub acks:  bool
+}
+
+/// Sends commands to the server on behalf of an attached backend. Clones share a connection and
+/// can be sent to other threads.
+#[derive(Clone)]
+pub struct Client{
+    out:      WsSender,
+    codec:    Codec,
+    graph:    GraphId,
+    next_req: Arc<AtomicUsize>
+}
+
+// the methods hand back whatever ws::Sender does, and ws::Error is big
+#[allow(clippy::result_large_err)]
+impl Client{
+    fn new(out: WsSender, codec: Codec, graph: GraphId) -> Client{
+        Client{
+            out,
+            codec,
+            graph,
+            next_req: Arc::new(AtomicUsize::new(0))
+        }
+    }
+
+    /// The graph this backend is attached to.
+    pub fn graph(&self) -> GraphId{
+        self.graph
+    }
+
+    /// Sends `command`, returning the RequestId that the server's Reply will carry.
+    pub fn send(&self, command: Command) -> WsResult<RequestId>{
+        let req = self.next_req.fetch_add(1, Ordering::Relaxed) as RequestId;
+        self.out.send(self.codec.encode(&Envelope{ req: Some(req), command })?)?;
+        Ok(req)
+    }
+
+    pub fn set_graph(&self, graph: GraphData) -> WsResult<RequestId>{
+        self.send(Command::SetGraph{ graph: Arc::new(graph) })
+    }
+    pub fn set_data<S: Into<DataId>>(&self, id: S, value: DataValue) -> WsResult<RequestId>{
+        self.send(Command::SetData{ id: id.into(), value })
+    }
+    pub fn add_link<S: Into<PortId>, T: Into<PortId>>(&self, source: S, target: T) -> WsResult<RequestId>{
+        self.send(Command::AddLink{ source: source.into(), target: target.into() })
+    }
+    pub fn del_link<S: Into<PortId>, T: Into<PortId>>(&self, source: S, target: T) -> WsResult<RequestId>{
+        self.send(Command::DelLink{ source: source.into(), target: target.into() })
+    }
+    pub fn add_node(&self, path: NodePath, node: Node) -> WsResult<RequestId>{
+        self.send(Command::AddNode{ path, node })
+    }
+    pub fn remove_node(&self, path: NodePath) -> WsResult<RequestId>{
+        self.send(Command::RemoveNode{ path })
+    }
+    pub fn move_node(&self, path: NodePath, to: NodePath) -> WsResult<RequestId>{
+        self.send(Command::MoveNode{ path, to })
+    }
+    pub fn replace_node(&self, path: NodePath, node: Node) -> WsResult<RequestId>{
+        self.send(Command::ReplaceNode{ path, node })
+    }
+    pub fn link_targets<S: Into<PortId>>(&self, source: S) -> WsResult<RequestId>{
+        self.send(Command::LinkTargets{ source: source.into() })
+    }
+    pub fn find_cycle(&self) -> WsResult<RequestId>{
+        self.send(Command::FindCycle)
+    }
+    pub fn undo(&self) -> WsResult<RequestId>{
+        self.send(Command::Undo)
+    }
+    pub fn redo(&self) -> WsResult<RequestId>{
+        self.send(Command::Redo)
+    }
+
+    pub fn close(&self) -> WsResult<()>{
+        self.out.close(CloseCode::Normal)
+    }
+}
+
+/// Receives the updates for an attached backend. Closures taking a `&Client` and an `Update`
+/// are Backends.
+pub trait Backend{
+    /// Called once the server has acknowledged the attach, before any updates.
+    fn on_attach(&mut self, _client: &Client){}
+
+    fn on_update(&mut self, client: &Client, update: Update);
+
+    fn on_close(&mut self){}
+
+    // lets Forward pass messages on undecoded, since decoded updates can't leave their thread
+    #[doc(hidden)]
+    #[allow(clippy::result_large_err)]
+    fn on_message(&mut self, client: &Client, msg: Message) -> WsResult<()>{
+        let incoming: Incoming = decode(msg)?;
+        self.on_update(client, incoming.update);
+        Ok(())
+    }
+}
+
+impl<F> Backend for F where F: FnMut(&Client, Update){
+    fn on_update(&mut self, client: &Client, update: Update){
+        self(client, update)
+    }
+}
+
+// broadcast updates carry a sequence number that backends don't need
+struct Incoming{
+    seq:    Option<Seq>,
+    update: Update
+}
+
+impl<'de> Deserialize<'de> for Incoming{
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error>{
+        let (update, seq) = wire::deserialize_with(deserializer, "seq")?;
+        Ok(Incoming{ seq, update })
+    }
+}
+
+#[allow(clippy::result_large_err)]
+fn decode<T: DeserializeOwned>(msg: Message) -> WsResult<T>{
+    match msg{
+        Message::Text(t) =>
+            serde_json::from_str(&t[..])
+                .map_err(|e| WsError::new(WsErrorKind::Protocol,
+                                          format!("JSON decoding failed {:?}", e))),
+        Message::Binary(b) =>
+            rmp_serde::from_slice(&b[..])
+                .map_err(|e| WsError::new(WsErrorKind::Protocol,
+                                          format!("MessagePack decoding failed {:?}", e)))
+    }
+}
+
+enum Attach{
+    // waiting for the graph list
+    Listed,
+    // waiting for the Attached response
+    Sent,
+    Done(Client)
+}
+
+struct Connection<B: Backend>{
+    out:     WsSender,
+    options: AttachOptions,
+    attach:  Attach,
+    backend: B
+}
+
+impl<B: Backend> Handler for Connection<B>{
+    fn build_request(&mut self, url: &Url) -> WsResult<Request>{
+        let mut req = Request::from_url(url)?;
+        req.add_protocol(self.options.codec.protocol_name());
+        Ok(req)
+    }
+
+    fn on_open(&mut self, _hs: Handshake) -> WsResult<()>{
+        trace!("Connected, waiting for the graph list");
+        Ok(())
+    }
+
+    fn on_message(&mut self, msg: Message) -> WsResult<()>{
+        match self.attach{
+            Attach::Done(ref client) => self.backend.on_message(client, msg),
+            Attach::Listed => {
+                // the server lists its graphs first, attaching any earlier would be ignored
+                let graphs: GraphList = decode(msg)?;
+                trace!("Server has graphs {:?}", graphs.list);
+                let attach = Command::BackendAttach{
+                    id:    self.options.graph,
+                    codec: None, // already chosen by the subprotocol
+                    acks:  self.options.acks
+                };
+                self.out.send(self.options.codec.encode(&Envelope::from(attach))?)?;
+                self.attach = Attach::Sent;
+                Ok(())
+            },
+            Attach::Sent => {
+                let incoming: Incoming = decode(msg)?;
+                match incoming.update{
+                    Update::Response(Reply{ response: Response::Attached{ id }, .. }) => {
+                        trace!("Attached to GraphId {}", id);
+                        let client = Client::new(self.out.clone(), self.options.codec, id);
+                        self.backend.on_attach(&client);
+                        self.attach = Attach::Done(client);
+                        Ok(())
+                    },
+                    other => Err(WsError::new(WsErrorKind::Protocol,
+                                              format!("Expected Attached, got {:?}", other)))
+                }
+            }
+        }
+    }
+
+    fn on_close(&mut self, code: CloseCode, reason: &str){
+        debug!("Connection closed because {:?} {}", code, reason);
+        self.backend.on_close();
+    }
+}
+
+/// Connects to the server at `url`, attaches and hands every update to `backend` until the
+/// connection closes. Blocks the calling thread.
+#[allow(clippy::result_large_err)]
+pub fn run<B: Backend>(url: &str, options: AttachOptions, backend: B) -> WsResult<()>{
+    let mut backend = Some(backend);
+    ws::connect(url, |out| Connection{
+        out,
+        options,
+        attach:  Attach::Listed,
+        backend: backend.take().expect("only one connection is made")
+    })
+}
+
+/// Decodes updates received by `spawn` on the thread that iterates over them.
+pub struct Updates{
+    rx: Receiver<Message>
+}
+
+impl Iterator for Updates{
+    type Item = Update;
+
+    fn next(&mut self) -> Option<Update>{
+        loop{
+            match decode::<Incoming>(self.rx.recv().ok()?){
+                Ok(incoming) => return Some(incoming.update),
+                Err(e)       => warn!("Skipping update that failed to decode: {:?}", e)
+            }
+        }
+    }
+}
+
+struct Forward{
+    tx:        Sender<Message>,
+    client_tx: Option<Sender<Client>>
+}
+
+impl Backend for Forward{
+    fn on_attach(&mut self, client: &Client){
+        if let Some(client_tx) = self.client_tx.take(){
+            let _ = client_tx.send(client.clone());
+        }
+    }
+
+    fn on_update(&mut self, _client: &Client, _update: Update){}
+
+    fn on_message(&mut self, _client: &Client, msg: Message) -> WsResult<()>{
+        // nobody is listening anymore, so stop
+        self.tx.send(msg)
+            .map_err(|_| WsError::new(WsErrorKind::Internal, "Updates were dropped"))
+    }
+}
+
+/// Like `run` but on its own thread. Returns once attached with a Client that can be used from
+/// any thread and the updates that arrive from then on, which end when the connection closes.
+#[allow(clippy::result_large_err)]
+pub fn spawn(url: String, options: AttachOptions)
+    -> WsResult<(JoinHandle<WsResult<()>>, Client, Updates)>
+{
+    let (tx, rx)               = channel();
+    let (client_tx, client_rx) = channel();
+    let handle = thread::Builder::new()
+        .name("backend".into())
+        .spawn(move ||{
+            run(&url, options, Forward{ tx, client_tx: Some(client_tx) })
+        })?;
+    match client_rx.recv(){
+        Ok(client) => Ok((handle, client, Updates{ rx })),
+        Err(_)     => Err(WsError::new(WsErrorKind::Internal,
+                                       "Connection closed before attaching"))
+    }
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+    use server::{Builder, Server};
+
+    use std::env;
+    use std::fs;
+    use std::net::SocketAddr;
+    use std::process;
+
+    fn start(name: &str) -> Server{
+        let root = env::temp_dir().join(format!("nodeeditor-backend-{}-{}", name, process::id()));
+        fs::create_dir_all(&root).unwrap();
+        let any_port: SocketAddr = "127.0.0.1:0".parse().unwrap();
+        Builder::new()
+            .root(root)
+            .http_addr(any_port)
+            .ws_addr(any_port)
+            .reload_addr(any_port)
+            .start()
+            .unwrap()
+    }
+
+    fn attach(server: &Server, options: AttachOptions) -> (JoinHandle<WsResult<()>>, Client, Updates){
+        spawn(format!("ws://{}", server.ws_addr()), options).unwrap()
+    }
+
+    #[test]
+    fn incoming_takes_sequenced_and_plain_updates(){
+        let sequenced = Sequenced{ seq: 4, update: Command::Redo.into() };
+        let plain     = Update::from(Reply{ req: Some(1), response: Response::Ok });
+        for codec in &[Codec::Json, Codec::MsgPack]{
+            let incoming: Incoming = decode(codec.encode(&sequenced).unwrap()).unwrap();
+            assert_eq!((incoming.seq, incoming.update), (Some(4), sequenced.update.clone()));
+            let incoming: Incoming = decode(codec.encode(&plain).unwrap()).unwrap();
+            assert_eq!((incoming.seq, incoming.update), (None, plain.clone()));
+        }
+    }
+
+    #[test]
+    fn attaching_tells_which_graph(){
+        let server = start("graph");
+        let (handle, client, _) = attach(&server, AttachOptions::default());
+        assert!(server.store().contains_key(client.graph()));
+
+        let options = AttachOptions{ graph: Some(client.graph()), ..AttachOptions::default() };
+        let (other_handle, other, _) = attach(&server, options);
+        assert_eq!(other.graph(), client.graph());
+        let options = AttachOptions{ graph: Some(40), codec: Codec::MsgPack, ..AttachOptions::default() };
+        let (made_handle, made, _) = attach(&server, options);
+        assert_eq!(made.graph(), 40);
+
+        server.shutdown().unwrap();
+        for handle in [handle, other_handle, made_handle]{
+            handle.join().unwrap().unwrap();
+        }
+    }
+
+    #[test]
+    fn acks_and_updates_reach_backends(){
+        let server = start("updates");
+        let options = AttachOptions{ acks: true, ..AttachOptions::default() };
+        let (handle, client, mut updates) = attach(&server, options);
+        let options = AttachOptions{ graph: Some(client.graph()), codec: Codec::MsgPack, acks: false };
+        let (other_handle, _, mut other_updates) = attach(&server, options);
+
+        let node = Node::Label{ data: "label".into() };
+        let req  = client.add_node(vec!["n".into()], node.clone()).unwrap();
+        let added = Update::from(Command::AddNode{ path: vec!["n".into()], node });
+        let acked = Update::from(Reply{ req: Some(req), response: Response::Ok });
+        // the ack goes out after the edit is broadcast
+        assert_eq!(updates.next(), Some(added.clone()));
+        assert_eq!(updates.next(), Some(acked));
+        assert_eq!(other_updates.next(), Some(added));
+
+        let req = client.set_data("x", DataValue::from(1)).unwrap();
+        // backends only send values to frontends
+        assert_eq!(updates.next(), Some(Update::from(Reply{ req: Some(req), response: Response::Ok })));
+        let graph = server.store().get(client.graph()).unwrap();
+        assert_eq!(graph.data.read().unwrap().data.get("x"), Some(&DataValue::from(1)));
+
+        server.shutdown().unwrap();
+        handle.join().unwrap().unwrap();
+        other_handle.join().unwrap().unwrap();
+        assert_eq!(updates.next(), None);
+        assert_eq!(other_updates.next(), None);
+    }
+}
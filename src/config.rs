@@ -0,0 +1,255 @@
+use serde::{Deserialize, Deserializer};
+use toml;
+
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::io::Read;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::Duration;
+
+pub static USAGE: &str = "\
+Options:
+    --config FILE        read settings from a TOML file, later options override it
+    --http ADDR          address to serve the client on
+    --ws ADDR            address of the graph websocket
+    --reload ADDR        address of the reload websocket
+    --root DIR           directory the client is served and compiled from
+    --graph-dir DIR      directory graphs are saved to and restored from, empty to not save them
+    --evaluate BOOL      run graphs on the server with the builtin behaviours after every edit
+    --file-threads N     number of threads reading files for the cache
+    --watch-debounce MS  milliseconds to wait for changes to settle before recompiling
+    --mime-type EXT=TYPE serve files ending in .EXT as TYPE, can be repeated
+    --index FILE         file served for requests to a directory
+    --listing BOOL       list directories without an index file, leaving out dotfiles
+    --fallback FILE      file served for missing paths without an extension
+    --cache-bytes N      most bytes of file contents kept in memory
+    --cache-entries N    most files kept in memory
+    --cache-max-file N   files bigger than N bytes are streamed from disk instead
+    --help               print this message
+
+Ports can be 0 to have the OS pick a free one, the bound addresses are logged.";
+
+/// Everything that can be set from a config file or the command line.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default)]
+pub struct Config{
+    pub http_addr:         SocketAddr,
+    pub ws_addr:           SocketAddr,
+    pub reload_addr:       SocketAddr,
+    pub root:              PathBuf,
+    /// Graphs aren't saved if this is None, which an empty string stands for in a config file.
+    #[serde(deserialize_with = "non_empty_path")]
+    pub graph_dir:         Option<PathBuf>,
+    /// Evaluates graphs with `Evaluator::with_builtins` unless the Builder is given another one.
+    pub evaluate:          bool,
+    pub file_threads:      usize,
+    pub watch_debounce_ms: u64,
+    /// Content-Types by file extension, on top of the built in ones.
+    pub mime_types:        BTreeMap<String, String>,
+    pub index:             String,
+    pub listing:           bool,
+    /// Relative to `root`, lets client side routes load the app.
+    pub fallback:          Option<PathBuf>,
+    /// The least recently used files are dropped once the cache holds more than this many bytes
+    /// or files. Encoded variants count as separate files.
+    pub cache_bytes:       usize,
+    pub cache_entries:     usize,
+    pub cache_max_file:    usize
+}
+
+impl Default for Config{
+    fn default() -> Config{
+        Config{
+            http_addr:         "127.0.0.1:3000".parse().unwrap(),
+            ws_addr:           "127.0.0.1:3001".parse().unwrap(),
+            reload_addr:       "127.0.0.1:3002".parse().unwrap(),
+            root:              "client/".into(),
+            graph_dir:         Some("graphs/".into()),
+            evaluate:          true,
+            file_threads:      4,
+            watch_debounce_ms: 200,
+            mime_types:        BTreeMap::new(),
+            index:             "index.html".into(),
+            listing:           false,
+            fallback:          None,
+            cache_bytes:       64 * 1024 * 1024,
+            cache_entries:     4096,
+            cache_max_file:    8 * 1024 * 1024
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum ConfigError{
+    Io(PathBuf, io::Error),
+    Toml(PathBuf, toml::de::Error),
+    Arg(String),
+    Help
+}
+
+impl fmt::Display for ConfigError{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result{
+        use self::ConfigError::*;
+        match *self{
+            Io(ref path, ref e)   => write!(f, "Couldn't read {:?}: {}", path, e),
+            Toml(ref path, ref e) => write!(f, "Invalid config in {:?}: {}", path, e),
+            Arg(ref s)            => write!(f, "{}", s),
+            Help                  => write!(f, "{}", USAGE)
+        }
+    }
+}
+
+type Result<T> = ::std::result::Result<T, ConfigError>;
+
+impl Config{
+    pub fn load(path: &Path) -> Result<Config>{
+        let mut s = String::new();
+        fs::File::open(path)
+            .and_then(|mut file| file.read_to_string(&mut s))
+            .map_err(|e| ConfigError::Io(path.into(), e))?;
+        toml::from_str(&s).map_err(|e| ConfigError::Toml(path.into(), e))
+    }
+
+    /// Builds a config from command line arguments, not including the program name. Settings
+    /// come from the defaults, then the `--config` file if there is one, then the other options.
+    pub fn from_args<I: IntoIterator<Item=String>>(args: I) -> Result<Config>{
+        let args: Vec<String> = args.into_iter().collect();
+        let mut pairs = Vec::new();
+        let mut iter  = args.iter();
+        while let Some(arg) = iter.next(){
+            if arg == "--help" || arg == "-h"{
+                return Err(ConfigError::Help);
+            }
+            match iter.next(){
+                Some(value) => pairs.push((arg, value)),
+                None => return Err(ConfigError::Arg(format!("{} needs a value", arg)))
+            }
+        }
+
+        let mut config = match pairs.iter().rfind(|&&(arg, _)| arg == "--config"){
+            Some(&(_, path)) => Config::load(Path::new(path))?,
+            None             => Config::default()
+        };
+        for (arg, value) in pairs{
+            match &arg[..]{
+                "--config"         => {},
+                "--http"           => config.http_addr         = parse(arg, value)?,
+                "--ws"             => config.ws_addr           = parse(arg, value)?,
+                "--reload"         => config.reload_addr       = parse(arg, value)?,
+                "--root"           => config.root              = value.into(),
+                "--graph-dir"      => config.graph_dir         = non_empty(value.into()),
+                "--evaluate"       => config.evaluate          = parse(arg, value)?,
+                "--file-threads"   => config.file_threads      = parse(arg, value)?,
+                "--watch-debounce" => config.watch_debounce_ms = parse(arg, value)?,
+                "--index"          => config.index             = value.clone(),
+                "--listing"        => config.listing           = parse(arg, value)?,
+                "--fallback"       => config.fallback          = Some(value.into()),
+                "--cache-bytes"    => config.cache_bytes       = parse(arg, value)?,
+                "--cache-entries"  => config.cache_entries     = parse(arg, value)?,
+                "--cache-max-file" => config.cache_max_file    = parse(arg, value)?,
+                "--mime-type"      => {
+                    let mut parts = value.splitn(2, '=');
+                    match (parts.next(), parts.next()){
+                        (Some(ext), Some(ty)) => { config.mime_types.insert(ext.into(), ty.into()); },
+                        _ => return Err(ConfigError::Arg(format!("{} needs EXT=TYPE", arg)))
+                    }
+                },
+                _ => return Err(ConfigError::Arg(format!("Unknown option {}", arg)))
+            }
+        }
+        Ok(config)
+    }
+
+    pub fn watch_debounce(&self) -> Duration{
+        Duration::from_millis(self.watch_debounce_ms)
+    }
+}
+
+fn non_empty(path: PathBuf) -> Option<PathBuf>{
+    if path.as_os_str().is_empty(){ None } else { Some(path) }
+}
+
+fn non_empty_path<'de, D: Deserializer<'de>>(deserializer: D) -> ::std::result::Result<Option<PathBuf>, D::Error>{
+    Ok(Option::<PathBuf>::deserialize(deserializer)?.and_then(non_empty))
+}
+
+fn parse<T: FromStr>(arg: &str, value: &str) -> Result<T>{
+    value.parse().map_err(|_| ConfigError::Arg(format!("Invalid value {:?} for {}", value, arg)))
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+    use std::env;
+
+    fn args(args: &[&str]) -> Vec<String>{
+        args.iter().map(|&arg| arg.to_string()).collect()
+    }
+
+    #[test]
+    fn defaults_without_args(){
+        let config = Config::from_args(args(&[])).unwrap();
+        assert_eq!(config.http_addr, Config::default().http_addr);
+        assert_eq!(config.graph_dir, Some("graphs/".into()));
+    }
+
+    #[test]
+    fn options_override_defaults(){
+        let config = Config::from_args(args(&["--http", "0.0.0.0:80", "--listing", "true",
+                                              "--mime-type", "wasm=application/wasm",
+                                              "--graph-dir", "saved", "--cache-bytes", "10",
+                                              "--evaluate", "false"]))
+            .unwrap();
+        assert_eq!(config.http_addr, "0.0.0.0:80".parse().unwrap());
+        assert!(config.listing);
+        assert_eq!(config.mime_types.get("wasm").map(|t| &t[..]), Some("application/wasm"));
+        assert_eq!(config.graph_dir, Some("saved".into()));
+        assert_eq!(config.cache_bytes, 10);
+        assert!(!config.evaluate);
+    }
+
+    #[test]
+    fn empty_graph_dir_turns_saving_off(){
+        assert_eq!(Config::from_args(args(&["--graph-dir", ""])).unwrap().graph_dir, None);
+        let config: Config = toml::from_str("graph_dir = \"\"").unwrap();
+        assert_eq!(config.graph_dir, None);
+        let config: Config = toml::from_str("graph_dir = \"saved\"").unwrap();
+        assert_eq!(config.graph_dir, Some("saved".into()));
+    }
+
+    #[test]
+    fn bad_args_are_errors(){
+        match Config::from_args(args(&["--help"])){
+            Err(ConfigError::Help) => {},
+            other => panic!("expected help, got {:?}", other)
+        }
+        for bad in [args(&["--http"]), args(&["--http", "nowhere"]),
+                        args(&["--frobnicate", "1"]), args(&["--mime-type", "wasm"])]{
+            match Config::from_args(bad.clone()){
+                Err(ConfigError::Arg(_)) => {},
+                other => panic!("expected an error for {:?}, got {:?}", bad, other)
+            }
+        }
+    }
+
+    #[test]
+    fn options_override_the_config_file(){
+        let path = env::temp_dir().join(format!("nodeeditor-config-{}.toml", ::std::process::id()));
+        fs::write(&path, "index = \"main.html\"\nfile_threads = 2\n").unwrap();
+        let config = Config::from_args(vec!["--file-threads".into(), "8".into(),
+                                            "--config".into(), path.to_string_lossy().into_owned()]);
+        fs::remove_file(&path).unwrap();
+        let config = config.unwrap();
+        assert_eq!(config.index, "main.html");
+        assert_eq!(config.file_threads, 8);
+
+        match Config::from_args(args(&["--config", "/nonexistent/config.toml"])){
+            Err(ConfigError::Io(..)) => {},
+            other => panic!("expected an io error, got {:?}", other)
+        }
+    }
+}
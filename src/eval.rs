@@ -0,0 +1,288 @@
+use std::collections::{BTreeMap, BTreeSet};
+
+use graph::{GraphData, Node, NodeId, PortId, DataId, DataValue, Command};
+
+/// Values of a node's ports keyed by PortId.
+pub type PortValues = BTreeMap<PortId, DataValue>;
+
+/// Port values are kept in `GraphData.data` under DataIds starting with this, apart from the
+/// values clients set.
+pub static PORT_PREFIX: &str = "port:";
+
+/// The DataId a port's value is kept under.
+pub fn port_data_id(port: &PortId) -> DataId{
+    format!("{}{}", PORT_PREFIX, port)
+}
+
+/// Computes a node's outputs from the values on its inputs, given the PortIds of its outputs.
+/// Inputs without a value are left out and outputs that aren't returned keep their value.
+pub type Behaviour = Box<dyn Fn(&PortValues, &[PortId]) -> PortValues + Send + Sync>;
+
+/// Runs graphs server-side.
+///
+/// A port's value is the entry in `GraphData.data` under `port_data_id`. Values flow along links
+/// into InPorts and BiPorts, then a node whose label names a registered behaviour computes the
+/// values of its OutPorts and BiPorts. Top-level nodes are evaluated in topological order, nodes
+/// in or downstream of a cycle are skipped.
+///
+/// An InPort linked from several ports takes the value of the source with the lowest PortId that
+/// has one, and is cleared to Nil once none of its sources has a value, e.g. after its links are
+/// deleted. A BiPort without an incoming value keeps what its own node last output. Nil counts
+/// as no value throughout.
+#[derive(Default)]
+pub struct Evaluator{
+    behaviours: BTreeMap<String, Behaviour>
+}
+
+impl Evaluator{
+    /// An evaluator with the behaviours that come with the server already registered.
+    pub fn with_builtins() -> Evaluator{
+        let mut evaluator = Evaluator::default();
+        evaluator.register("Add", Box::new(|inputs: &PortValues, outputs: &[PortId]|
+            broadcast(outputs, arithmetic(inputs, 0, |a, b| a.wrapping_add(b), |a, b| a + b))));
+        evaluator.register("Multiply", Box::new(|inputs: &PortValues, outputs: &[PortId]|
+            broadcast(outputs, arithmetic(inputs, 1, |a, b| a.wrapping_mul(b), |a, b| a * b))));
+        evaluator
+    }
+
+    /// Runs `behaviour` for every node labelled `name`, replacing any behaviour already there.
+    pub fn register<S: Into<String>>(&mut self, name: S, behaviour: Behaviour){
+        self.behaviours.insert(name.into(), behaviour);
+    }
+
+    /// Evaluates `graph` without modifying it, returning SetData commands for every value that
+    /// changed. If only the value under `changed` differs from when the graph was last
+    /// evaluated, only the nodes that value can affect are evaluated again.
+    pub fn evaluate(&self, graph: &GraphData, changed: Option<&DataId>) -> Vec<Command>{
+        let affected = changed.map(|id| affected_by(graph, id));
+        if affected.as_ref().is_some_and(|affected| affected.is_empty()){
+            return Vec::new();
+        }
+        // only what evaluating changes is kept here, everything else is read from the graph
+        let mut values: BTreeMap<DataId, DataValue> = BTreeMap::new();
+
+        let mut sources: BTreeMap<&PortId, Vec<&PortId>> = BTreeMap::new();
+        for (source, targets) in graph.links.iter(){
+            for target in targets{
+                // links are ordered by source, so these are too
+                sources.entry(target).or_default().push(source);
+            }
+        }
+
+        let (order, unordered) = graph.partial_order();
+        if !unordered.is_empty(){
+            warn!("Not evaluating nodes in or after a cycle: {:?}", unordered);
+        }
+
+        for id in order.iter(){
+            if affected.as_ref().is_some_and(|affected| !affected.contains(id)){
+                continue;
+            }
+            let node = &graph.nodes[id];
+            let mut ports = BTreeMap::new();
+            node.collect_ports(id, &mut ports);
+
+            let mut inputs  = PortValues::new();
+            let mut outputs = Vec::new();
+            for (port, node) in ports.iter(){
+                let data_id = port_data_id(port);
+                match **node{
+                    Node::InPort(_) | Node::BiPort(_) => {
+                        let incoming = sources.get(port).and_then(|sources|
+                            sources.iter()
+                                   .filter_map(|source| value(graph, &values, &port_data_id(source)))
+                                   .next()
+                                   .cloned());
+                        match (incoming, *node){
+                            (Some(value), _) => { values.insert(data_id.clone(), value); },
+                            (None, Node::InPort(_)) if value(graph, &values, &data_id).is_some() => {
+                                values.insert(data_id.clone(), DataValue::Nil);
+                            },
+                            _ => {}
+                        }
+                        if let Some(value) = value(graph, &values, &data_id){
+                            inputs.insert(port.clone(), value.clone());
+                        }
+                    },
+                    _ => {}
+                }
+                match **node{
+                    Node::OutPort(_) | Node::BiPort(_) => outputs.push(port.clone()),
+                    _ => {}
+                }
+            }
+
+            let behaviour = behaviour_name(node, &graph.data)
+                .and_then(|name| self.behaviours.get(name));
+            if let Some(behaviour) = behaviour{
+                for (port, value) in behaviour(&inputs, &outputs[..]){
+                    if outputs.contains(&port){
+                        values.insert(port_data_id(&port), value);
+                    }
+                    else{
+                        warn!("Node {} tried to output to {}, which isn't one of its outputs", id, port);
+                    }
+                }
+            }
+        }
+
+        values.into_iter()
+              .filter(|(id, value)| graph.data.get(id).unwrap_or(&DataValue::Nil) != value)
+              .map(|(id, value)| Command::SetData{ id, value })
+              .collect()
+    }
+}
+
+// the value under `id` as evaluation has left it so far
+fn value<'a>(graph: &'a GraphData, values: &'a BTreeMap<DataId, DataValue>, id: &DataId)
+    -> Option<&'a DataValue>
+{
+    values.get(id)
+          .or_else(|| graph.data.get(id))
+          .filter(|&value| *value != DataValue::Nil)
+}
+
+/// Top-level nodes whose ports or children use the value under `id`, along with everything
+/// downstream of them.
+fn affected_by(graph: &GraphData, id: &DataId) -> BTreeSet<NodeId>{
+    let owner = id.strip_prefix(PORT_PREFIX)
+                  .and_then(|port| graph.port_owners().remove(port));
+    let users = graph.nodes
+                     .iter()
+                     .filter(|&(_, node)| uses_data(node, id))
+                     .map(|(node_id, _)| node_id.clone());
+    let mut affected = BTreeSet::new();
+    for node_id in owner.into_iter().chain(users){
+        affected.extend(graph.downstream(&node_id));
+        affected.insert(node_id);
+    }
+    affected
+}
+
+fn uses_data(node: &Node, id: &DataId) -> bool{
+    match *node{
+        Node::Label{ ref data } | Node::Knob{ ref data } | Node::Button{ ref data } => data == id,
+        Node::Labelled{ ref data, ref nodes } => data == id || nodes.values().any(|node| uses_data(node, id)),
+        Node::Container{ ref nodes } => nodes.iter().any(|node| uses_data(node, id)),
+        _ => false
+    }
+}
+
+/// A node's label, which names its behaviour.
+fn behaviour_name<'a>(node: &Node, values: &'a BTreeMap<DataId, DataValue>) -> Option<&'a String>{
+    match *node{
+        Node::Label{ ref data } | Node::Labelled{ ref data, .. } =>
+            match values.get(data){
+                Some(DataValue::String(name)) => Some(name),
+                _ => None
+            },
+        _ => None
+    }
+}
+
+/// Folds every numeric input with `int_op`, switching to `float_op` once a Float is involved.
+fn arithmetic<I, F>(inputs: &PortValues, identity: i32, int_op: I, float_op: F) -> DataValue
+    where I: Fn(i32, i32) -> i32,
+          F: Fn(f32, f32) -> f32
+{
+    inputs.values().fold(DataValue::Int(identity), |acc, value|
+        match (acc, value){
+            (DataValue::Int(a),   &DataValue::Int(b))   => DataValue::Int(int_op(a, b)),
+            (DataValue::Int(a),   &DataValue::Float(b)) => DataValue::Float(float_op(a as f32, b)),
+            (DataValue::Float(a), &DataValue::Int(b))   => DataValue::Float(float_op(a, b as f32)),
+            (DataValue::Float(a), &DataValue::Float(b)) => DataValue::Float(float_op(a, b)),
+            (acc, _) => acc // non-numeric inputs are ignored
+        })
+}
+
+/// Sets every output to `value`.
+fn broadcast(outputs: &[PortId], value: DataValue) -> PortValues{
+    outputs.iter().map(|port| (port.clone(), value.clone())).collect()
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+    use graph::PortSpec;
+
+    fn labelled(label: &str, ports: &[(&str, Node)]) -> Node{
+        Node::Labelled{
+            data:  label.into(),
+            nodes: ports.iter().map(|&(id, ref port)| (id.to_string(), port.clone())).collect()
+        }
+    }
+
+    // x and y both feed sum, which adds up its inputs
+    fn sum() -> GraphData{
+        let mut graph = GraphData::default();
+        let out = Node::OutPort(PortSpec::default());
+        let inp = Node::InPort(PortSpec::default());
+        graph.nodes.insert("x".into(), labelled("x_label", &[("x_out", out.clone())]));
+        graph.nodes.insert("y".into(), labelled("y_label", &[("y_out", out.clone())]));
+        graph.nodes.insert("sum".into(), labelled("sum_label", &[("sum_in", inp), ("sum_out", out)]));
+        for source in ["x_out", "y_out"]{
+            graph.links.entry(source.into()).or_default().insert("sum_in".into());
+        }
+        graph.data.insert("sum_label".into(), DataValue::from("Add"));
+        graph.data.insert(port_data_id(&"x_out".into()), DataValue::from(2));
+        graph.data.insert(port_data_id(&"y_out".into()), DataValue::from(3));
+        graph
+    }
+
+    fn apply(graph: &mut GraphData, commands: Vec<Command>){
+        for command in commands{
+            match command{
+                Command::SetData{ id, value } => { graph.data.insert(id, value); },
+                other => panic!("evaluating produced {:?}", other)
+            }
+        }
+    }
+
+    fn port_value<'a>(graph: &'a GraphData, port: &str) -> Option<&'a DataValue>{
+        graph.data.get(&port_data_id(&port.into()))
+    }
+
+    #[test]
+    fn the_lowest_source_wins(){
+        let mut graph = sum();
+        let changes = Evaluator::with_builtins().evaluate(&graph, None);
+        apply(&mut graph, changes);
+        assert_eq!(port_value(&graph, "sum_in"), Some(&DataValue::from(2)));
+        assert_eq!(port_value(&graph, "sum_out"), Some(&DataValue::from(2)));
+        assert!(Evaluator::with_builtins().evaluate(&graph, None).is_empty());
+    }
+
+    #[test]
+    fn unlinked_inputs_are_cleared(){
+        let evaluator = Evaluator::with_builtins();
+        let mut graph = sum();
+        let changes = evaluator.evaluate(&graph, None);
+        apply(&mut graph, changes);
+        graph.links.clear();
+        let changes = evaluator.evaluate(&graph, None);
+        apply(&mut graph, changes);
+        assert_eq!(port_value(&graph, "sum_in"), Some(&DataValue::Nil));
+        assert_eq!(port_value(&graph, "sum_out"), Some(&DataValue::from(0)));
+    }
+
+    #[test]
+    fn only_affected_nodes_are_evaluated(){
+        let evaluator = Evaluator::with_builtins();
+        let mut graph = sum();
+        let changes = evaluator.evaluate(&graph, None);
+        apply(&mut graph, changes);
+        // a node that was never evaluated, which only a full evaluation would get to
+        graph.nodes.insert("lone".into(), labelled("sum_label", &[("lone_out", Node::OutPort(PortSpec::default()))]));
+
+        assert!(evaluator.evaluate(&graph, Some(&"unused".into())).is_empty());
+        graph.data.insert(port_data_id(&"x_out".into()), DataValue::from(5));
+        let changes = evaluator.evaluate(&graph, Some(&port_data_id(&"x_out".into())));
+        apply(&mut graph, changes);
+        assert_eq!(port_value(&graph, "sum_out"), Some(&DataValue::from(5)));
+        assert_eq!(port_value(&graph, "lone_out"), None);
+        // changing the label reaches every node that uses it
+        let changes = evaluator.evaluate(&graph, Some(&"sum_label".into()));
+        apply(&mut graph, changes);
+        assert_eq!(port_value(&graph, "lone_out"), Some(&DataValue::from(0)));
+    }
+}
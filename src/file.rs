@@ -1,27 +1,99 @@
 // guided by https://github.com/stephank/hyper-staticfile/blob/554215012b589288750406362527b6e94d5464b7/src/requested_path.rs
 
 use hyper::server::{Request, Response, Service};
-use hyper::Error;
-use futures::{Future, future};
+use hyper::{Error, Method, Body, Chunk};
+use hyper::header::{Headers, Header, EntityTag, HttpDate, ContentType};
+use bytes::Bytes;
+use futures::{Future, Stream, Sink, Async, future, stream};
+use futures::sync::oneshot::Canceled;
+use futures_cpupool::{CpuPool, Builder as PoolBuilder};
 
-use std::path::{PathBuf};
+use std::cmp::min;
+use std::collections::{BTreeMap, VecDeque};
+use std::fs;
+use std::fs::File;
+use std::path::{Path, PathBuf};
 use std::ops::Deref;
 use std::rc::Rc;
 use std::net::SocketAddr;
 use std::sync::Arc;
 use std::io;
-use std::borrow::Borrow;
+use std::io::{Read, Seek, SeekFrom};
+use std::time::{SystemTime, UNIX_EPOCH};
 
 use filecache::FileCache;
-use filethread::Response as FileResponse;
+use filethread::Encoding;
+use mimetypes::MimeTypes;
+
+// threads that stream files too big for the cache, apart from the file threads so a few big
+// downloads can't hold up everything else
+const STREAM_THREADS: usize = 2;
+// bytes read from disk at a time when streaming
+const STREAM_CHUNK: u64 = 64 * 1024;
+// more ranges than this in one request are answered with the whole file
+const MAX_RANGES: usize = 16;
+
+/// How requested paths map to files.
+pub struct FileOptions{
+    pub root:       PathBuf,
+    /// Content-Types by extension, on top of the built in ones.
+    pub mime_types: BTreeMap<String, String>,
+    /// File served for requests to a directory.
+    pub index:      String,
+    /// Lists the contents of directories without an index file.
+    pub listing:    bool,
+    /// File under `root` served for missing paths without an extension, so client side routes
+    /// load the app.
+    pub fallback:   Option<PathBuf>
+}
 
 pub struct FileServerInternal{
-    root:  PathBuf,
-    cache: FileCache,
+    root:       PathBuf,
+    cache:      FileCache,
+    streams:    CpuPool,
+    mime_types: MimeTypes,
+    index:      String,
+    listing:    bool,
+    fallback:   Option<PathBuf>
+}
+
+// what a request path refers to
+enum Target{
+    File(PathBuf),
+    // a directory's index file and the directory itself
+    Index(PathBuf, PathBuf),
+    // directories get a trailing slash so relative links in their index resolve inside them
+    Redirect(String)
+}
+
+// what to do when the requested file doesn't exist
+enum Missing{
+    NotFound,
+    List(PathBuf),
+    Fallback(PathBuf)
+}
+
+// request details needed once the file has been fetched
+struct Requested{
+    method:  Method,
+    headers: Headers,
+    reqpath: String,
+    reqaddr: String
 }
 
 impl FileServerInternal{
-    fn decode_path(&self, req: &Request) -> PathBuf{
+    fn root(&self) -> PathBuf{
+        if self.root.is_relative(){
+            ::std::env::current_dir().unwrap()
+                                     .join(self.root.as_path())
+        }
+        else{
+            self.root.clone()
+        }
+    }
+
+    // the file under the root that the request path refers to, whatever it turns out to be
+    fn resolve(&self, reqpath: &str) -> PathBuf{
         use std::str::FromStr;
         use std::path::Component;
         use regex::{Regex, Replacer, Captures};
@@ -70,20 +142,11 @@ impl FileServerInternal{
         }
         
         let without_percent = PathBuf::from(String::from(
-            PERCENT_RE.replace_all(req.path(), PercentReplacer))
+            PERCENT_RE.replace_all(reqpath, PercentReplacer))
         );
        
         // strip parent dir ("..") from the relative path before adding to base
-        let root = {
-            if self.root.is_relative(){
-                ::std::env::current_dir().unwrap()
-                                         .join(self.root.as_path())
-            }
-            else{
-                self.root.clone()
-            }
-        };
-        root.join(
+        self.root().join(
             without_percent.components().fold(PathBuf::new(),
             |mut out, c|
             match c{
@@ -98,6 +161,74 @@ impl FileServerInternal{
                 _ => out
             }))
     }
+
+    // `is_dir` says whether `path` was found to be a directory, which is checked on a file thread
+    fn target(&self, reqpath: &str, path: PathBuf, is_dir: bool) -> Target{
+        if !is_dir{
+            Target::File(path)
+        }
+        else if !reqpath.ends_with('/'){
+            Target::Redirect(format!("{}/", reqpath))
+        }
+        else{
+            Target::Index(path.join(&self.index), path)
+        }
+    }
+
+    fn missing(&self, target: &Target) -> Missing{
+        match *target{
+            Target::Index(_, ref dir) if self.listing => Missing::List(dir.clone()),
+            Target::File(ref path) if path.extension().is_none() =>
+                match self.fallback{
+                    Some(ref fallback) => Missing::Fallback(self.root().join(fallback)),
+                    None               => Missing::NotFound
+                },
+            _ => Missing::NotFound
+        }
+    }
+}
+
+fn escape_html(s: &str) -> String{
+    s.replace('&', "&amp;")
+     .replace('<', "&lt;")
+     .replace('>', "&gt;")
+     .replace('"', "&quot;")
+}
+
+fn escape_href(s: &str) -> String{
+    s.bytes().map(|b| match b{
+        b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => (b as char).to_string(),
+        _ => format!("%{:02X}", b)
+    }).collect()
+}
+
+fn listing(dir: &Path, reqpath: &str) -> io::Result<String>{
+    let mut entries = Vec::new();
+    for entry in fs::read_dir(dir)?{
+        let entry = entry?;
+        let mut name = entry.file_name().to_string_lossy().into_owned();
+        // dotfiles are hidden, like ls does
+        if name.starts_with('.'){
+            continue;
+        }
+        if entry.file_type()?.is_dir(){
+            name.push('/');
+        }
+        entries.push(name);
+    }
+    entries.sort();
+    let title = escape_html(reqpath);
+    let mut html = format!("<!DOCTYPE html><title>Index of {0}</title><h1>Index of {0}</h1><ul>", title);
+    if reqpath != "/"{
+        html.push_str("<li><a href=\"../\">../</a></li>");
+    }
+    for name in entries{
+        let (stem, slash) = if name.ends_with('/'){ (&name[..name.len() - 1], "/") } else{ (&name[..], "") };
+        html.push_str(&format!("<li><a href=\"{}{}\">{}</a></li>",
+                               escape_href(stem), slash, escape_html(&name)));
+    }
+    html.push_str("</ul>");
+    Ok(html)
 }
 
 fn io_error(io: io::Error, path: &str, reqpath: &String, reqaddr: &String) -> Response{
@@ -125,7 +256,419 @@ fn io_error(io: io::Error, path: &str, reqpath: &String, reqaddr: &String) -> Re
     }
 }
 
-type ResponseFuture = Box<Future<Item=Response, Error=Error>>;
+// If-Modified-Since is only considered without If-None-Match, as RFC 7232 section 6 asks
+// HTTP dates only have whole seconds, mtimes usually have more, which the client never saw
+fn seconds(date: HttpDate) -> u64{
+    SystemTime::from(date).duration_since(UNIX_EPOCH).map(|since| since.as_secs()).unwrap_or(0)
+}
+
+fn not_modified(headers: &Headers, etag: &EntityTag, modified: HttpDate) -> bool{
+    use hyper::header::{IfNoneMatch, IfModifiedSince};
+    match headers.get::<IfNoneMatch>(){
+        Some(&IfNoneMatch::Any)        => true,
+        Some(IfNoneMatch::Items(tags)) => tags.iter().any(|tag| tag.weak_eq(etag)),
+        None => match headers.get::<IfModifiedSince>(){
+            Some(&IfModifiedSince(since)) => seconds(modified) <= seconds(since),
+            None => false
+        }
+    }
+}
+
+// which parts of a file to send
+enum Ranges{
+    Full,
+    Partial(Vec<(u64, u64)>),
+    Unsatisfiable
+}
+
+// sorts `ranges` and merges those that overlap or touch, so no byte is sent twice
+fn coalesce(mut ranges: Vec<(u64, u64)>) -> Vec<(u64, u64)>{
+    ranges.sort();
+    let mut merged: Vec<(u64, u64)> = Vec::with_capacity(ranges.len());
+    for (from, to) in ranges{
+        match merged.last_mut(){
+            Some(last) if from <= last.1.saturating_add(1) => {
+                last.1 = last.1.max(to);
+                continue;
+            },
+            _ => {}
+        }
+        merged.push((from, to));
+    }
+    merged
+}
+
+fn requested_ranges(headers: &Headers, etag: &EntityTag, modified: HttpDate, len: u64) -> Ranges{
+    use hyper::header::{Range, IfRange};
+    let specs = match headers.get::<Range>(){
+        Some(Range::Bytes(specs)) => specs,
+        _ => return Ranges::Full
+    };
+    // the ranges were worked out from the version the client has, another one gets sent whole
+    let unchanged = match headers.get::<IfRange>(){
+        Some(IfRange::EntityTag(tag)) => tag.strong_eq(etag),
+        Some(&IfRange::Date(date))    => seconds(modified) == seconds(date),
+        None                          => true
+    };
+    if !unchanged{
+        return Ranges::Full;
+    }
+    let ranges = coalesce(specs.iter().filter_map(|spec| spec.to_satisfiable_range(len)).collect());
+    if ranges.is_empty(){
+        Ranges::Unsatisfiable
+    }
+    else if ranges.len() > MAX_RANGES{
+        // lots of small ranges cost more to answer than the file itself
+        Ranges::Full
+    }
+    else{
+        Ranges::Partial(ranges)
+    }
+}
+
+// a piece of a response body, either bytes of its own or an inclusive range of the file
+enum Part{
+    Bytes(Bytes),
+    File(u64, u64)
+}
+
+impl Part{
+    fn len(&self) -> u64{
+        match *self{
+            Part::Bytes(ref bytes)  => bytes.len() as u64,
+            Part::File(from, to)    => to - from + 1
+        }
+    }
+}
+
+// sets the status and content headers for `ranges` of a file `len` bytes long, returning what
+// the body is made of
+fn ranged(res: &mut Response, ranges: Ranges, len: u64, content_type: ContentType,
+          etag: &EntityTag) -> Vec<Part>
+{
+    use hyper::StatusCode;
+    use hyper::header::{ContentRange, ContentRangeSpec};
+    let content_range = |from, to| ContentRangeSpec::Bytes{
+        range:           Some((from, to)),
+        instance_length: Some(len)
+    };
+    match ranges{
+        Ranges::Full => {
+            res.headers_mut().set(content_type);
+            if len == 0{ Vec::new() } else{ vec![Part::File(0, len - 1)] }
+        },
+        Ranges::Partial(ref ranges) if ranges.len() == 1 => {
+            let (from, to) = ranges[0];
+            res.set_status(StatusCode::PartialContent);
+            res.headers_mut().set(content_type);
+            res.headers_mut().set(ContentRange(content_range(from, to)));
+            vec![Part::File(from, to)]
+        },
+        Ranges::Partial(ranges) => {
+            // the ETag is unique enough that it won't turn up in the file
+            let boundary = format!("byteranges-{}", etag.tag());
+            let mut parts = Vec::new();
+            for (from, to) in ranges{
+                let head = format!("--{}\r\n{}: {}\r\n{}: {}\r\n\r\n",
+                                   boundary,
+                                   ContentType::header_name(), content_type,
+                                   ContentRange::header_name(),
+                                   ContentRange(content_range(from, to)));
+                parts.push(Part::Bytes(Bytes::from(head)));
+                parts.push(Part::File(from, to));
+                parts.push(Part::Bytes(Bytes::from_static(b"\r\n")));
+            }
+            parts.push(Part::Bytes(Bytes::from(format!("--{}--\r\n", boundary))));
+            res.set_status(StatusCode::PartialContent);
+            res.headers_mut().set_raw("Content-Type",
+                                      format!("multipart/byteranges; boundary={}", boundary));
+            parts
+        },
+        Ranges::Unsatisfiable => {
+            res.set_status(StatusCode::RangeNotSatisfiable);
+            res.headers_mut().set(ContentRange(ContentRangeSpec::Bytes{
+                range:           None,
+                instance_length: Some(len)
+            }));
+            Vec::new()
+        }
+    }
+}
+
+// a body made of `parts` of a cached file, which shares the file's buffer if it's a single part
+fn in_memory(file: &Bytes, parts: Vec<Part>) -> Body{
+    let slice = |from: u64, to: u64| file.slice(from as usize, to as usize + 1);
+    if parts.len() == 1{
+        if let Part::File(from, to) = parts[0]{
+            return Body::from(slice(from, to));
+        }
+    }
+    let mut body = Vec::new();
+    for part in parts{
+        match part{
+            Part::Bytes(bytes)   => body.extend_from_slice(&bytes),
+            Part::File(from, to) => body.extend_from_slice(&slice(from, to))
+        }
+    }
+    Body::from(body)
+}
+
+// reads `parts` of the file at `path` a chunk at a time
+struct Streamer{
+    path:  PathBuf,
+    file:  Option<File>,
+    parts: VecDeque<Part>
+}
+
+impl Streamer{
+    fn next_chunk(&mut self) -> io::Result<Option<Chunk>>{
+        match self.parts.pop_front(){
+            None => Ok(None),
+            Some(Part::Bytes(bytes)) => Ok(Some(Chunk::from(bytes))),
+            Some(Part::File(from, to)) => {
+                if self.file.is_none(){
+                    self.file = Some(File::open(&self.path)?);
+                }
+                let file = self.file.as_mut().unwrap();
+                let len  = min(STREAM_CHUNK, to - from + 1);
+                let mut buf = vec![0; len as usize];
+                file.seek(SeekFrom::Start(from))?;
+                // fails if the file got shorter, which ends the body early
+                file.read_exact(&mut buf)?;
+                if from + len <= to{
+                    self.parts.push_front(Part::File(from + len, to));
+                }
+                Ok(Some(Chunk::from(buf)))
+            }
+        }
+    }
+}
+
+// a body that reads `parts` of the file at `path` on `pool` as the client takes them
+fn streamed(pool: &CpuPool, path: PathBuf, parts: Vec<Part>) -> Body{
+    let (sender, body) = Body::pair();
+    let mut streamer = Streamer{ path, file: None, parts: parts.into_iter().collect() };
+    let chunks = stream::poll_fn(move ||{
+            let next = streamer.next_chunk();
+            if next.is_err(){
+                streamer.parts.clear();
+            }
+            next.map(Async::Ready)
+        })
+        // an error is passed on as the body's last item, which aborts the response
+        .then(|chunk| Ok::<_, ()>(chunk.map_err(Error::from)));
+    pool.spawn(chunks.forward(sender.sink_map_err(|_| ()))).forget();
+    body
+}
+
+// the best encoding the client accepts, brotli wins when it's as welcome as gzip
+fn negotiate(headers: &Headers) -> Encoding{
+    use hyper::header::{AcceptEncoding, Encoding as Coding, q};
+    let accepted = match headers.get::<AcceptEncoding>(){
+        Some(AcceptEncoding(accepted)) => accepted,
+        None => return Encoding::Identity
+    };
+    let quality = |coding: Coding| accepted.iter()
+        .filter(|accepted| accepted.item == coding)
+        .map(|accepted| accepted.quality)
+        .max()
+        .unwrap_or(q(0u16));
+    let (brotli, gzip) = (quality(Coding::Brotli), quality(Coding::Gzip));
+    if brotli > q(0u16) && brotli >= gzip{
+        Encoding::Brotli
+    }
+    else if gzip > q(0u16){
+        Encoding::Gzip
+    }
+    else{
+        Encoding::Identity
+    }
+}
+
+type ResponseFuture = Box<dyn Future<Item=Response, Error=Error>>;
+
+// a file thread only goes away when the server is stopping
+fn finished<T>(result: Result<io::Result<T>, Canceled>) -> io::Result<T>{
+    result.unwrap_or_else(|_| Err(io::Error::other("The file threads stopped")))
+}
+
+// an ETag for a file that isn't read into memory, from what its metadata says
+fn metadata_etag(modified: SystemTime, len: u64) -> String{
+    let since = modified.duration_since(UNIX_EPOCH).unwrap_or_default();
+    format!("{:x}.{:x}-{:x}", since.as_secs(), since.subsec_nanos(), len)
+}
+
+// what a response body comes from
+enum Contents{
+    Memory(Bytes),
+    // too big for the cache, so it's streamed from disk
+    Disk(PathBuf)
+}
+
+// a file about to be sent, with what the response says about it
+struct Found{
+    contents: Contents,
+    len:      u64,
+    modified: SystemTime,
+    etag:     String,
+    encoding: Encoding
+}
+
+impl FileServer{
+    // checks what `path` is on a file thread, then serves it
+    fn serve(&self, requested: Rc<Requested>, path: PathBuf, missing: Missing) -> ResponseFuture{
+        let this = self.clone();
+        let stat_path = path.clone();
+        Box::new(self.cache
+            .run(move || fs::metadata(stat_path))
+            .then(move |metadata| this.serve_metadata(requested, path, finished(metadata), missing)))
+    }
+
+    // files the cache would keep are fetched through it, bigger ones are streamed from disk
+    fn serve_metadata(&self, requested: Rc<Requested>, path: PathBuf,
+                      metadata: io::Result<fs::Metadata>, missing: Missing) -> ResponseFuture
+    {
+        match metadata{
+            Ok(ref metadata) if !self.cache.keeps(metadata.len()) => {
+                let modified = match metadata.modified(){
+                    Ok(modified) => modified,
+                    Err(io)      => return self.failed(&requested, &path, io, missing)
+                };
+                let found = Found{
+                    len:      metadata.len(),
+                    etag:     metadata_etag(modified, metadata.len()),
+                    modified,
+                    // compressing would mean holding the whole file, so it's sent as it is
+                    encoding: Encoding::Identity,
+                    contents: Contents::Disk(path.clone())
+                };
+                Box::new(future::ok(self.respond(&requested, &path, found)))
+            },
+            Ok(_)   => self.fetch(requested, path, missing),
+            Err(io) => self.failed(&requested, &path, io, missing)
+        }
+    }
+
+    // fetches `path` from the cache, in the best encoding the client accepts
+    fn fetch(&self, requested: Rc<Requested>, path: PathBuf, missing: Missing) -> ResponseFuture{
+        let path = Arc::new(path);
+        let encoding =
+            if self.mime_types.compressible(&path){ negotiate(&requested.headers) }
+            else{ Encoding::Identity };
+        let this = self.clone();
+
+        Box::new(self.cache
+            .fetch((path.clone(), encoding))
+            .then(move |r| -> ResponseFuture{
+                match finished(r){
+                    Ok(smf) => {
+                        let (modified, ref file, ref etag) = *smf;
+                        let found = Found{
+                            contents: Contents::Memory(file.clone()),
+                            len:      file.len() as u64,
+                            modified,
+                            etag:     etag.clone(),
+                            encoding
+                        };
+                        Box::new(future::ok(this.respond(&requested, &path, found)))
+                    },
+                    Err(io) => this.failed(&requested, &path, io, missing)
+                }
+            }))
+    }
+
+    // does what `missing` says if `path` doesn't exist, otherwise answers with the error
+    fn failed(&self, requested: &Rc<Requested>, path: &Path, io: io::Error, missing: Missing)
+        -> ResponseFuture
+    {
+        use hyper::header;
+
+        let path_str = path.to_str().unwrap_or("<nonunicode>").to_owned();
+        if io.kind() != io::ErrorKind::NotFound{
+            return Box::new(future::ok(io_error(io, &path_str, &requested.reqpath,
+                                                &requested.reqaddr)));
+        }
+        match missing{
+            Missing::List(dir) => {
+                let requested = requested.clone();
+                let reqpath   = requested.reqpath.clone();
+                Box::new(self.cache
+                    .run(move || listing(&dir, &reqpath))
+                    .then(move |html|{
+                        let Requested{ ref method, ref reqpath, ref reqaddr, .. } = *requested;
+                        Ok(match finished(html){
+                            Ok(html) => {
+                                info!("{:>20} - 200 - listing {}", reqaddr, path_str);
+                                let mut res = Response::new()
+                                    .with_header(header::ContentLength(html.len() as u64))
+                                    .with_header(header::ContentType::html());
+                                if *method == Method::Get{
+                                    res.set_body(Body::from(html));
+                                }
+                                res
+                            },
+                            Err(io) => io_error(io, &path_str, reqpath, reqaddr)
+                        })
+                    }))
+            },
+            Missing::Fallback(fallback) => {
+                trace!("{:>20} - falling back from {}", requested.reqaddr, path_str);
+                self.serve(requested.clone(), fallback, Missing::NotFound)
+            },
+            Missing::NotFound => Box::new(future::ok(io_error(io, &path_str, &requested.reqpath,
+                                                              &requested.reqaddr)))
+        }
+    }
+
+    fn respond(&self, requested: &Requested, path: &Path, found: Found) -> Response{
+        use hyper::{StatusCode, header};
+
+        let path_str = path.to_str().unwrap_or("<nonunicode>");
+        let Requested{ ref method, ref headers, ref reqaddr, .. } = *requested;
+        let compressible = self.mime_types.compressible(path);
+        let modified = header::HttpDate::from(found.modified);
+        let etag = header::EntityTag::strong(found.etag);
+        if not_modified(headers, &etag, modified){
+            info!("{:>20} - 304 - {}", reqaddr, path_str);
+            let mut res = Response::new()
+                .with_status(StatusCode::NotModified)
+                .with_header(header::ETag(etag))
+                .with_header(header::LastModified(modified));
+            if compressible{
+                res.headers_mut().set_raw("Vary", "Accept-Encoding");
+            }
+            return res;
+        }
+        let ranges = requested_ranges(headers, &etag, modified, found.len);
+        let mut res = Response::new()
+            .with_header(header::AcceptRanges(vec![header::RangeUnit::Bytes]))
+            .with_header(header::LastModified(modified));
+        if compressible{
+            // caches have to keep each encoding apart
+            res.headers_mut().set_raw("Vary", "Accept-Encoding");
+        }
+        if found.encoding != Encoding::Identity{
+            res.headers_mut().set_raw("Content-Encoding", found.encoding.name());
+        }
+        // stops browsers second guessing the Content-Type, strict settings
+        // refuse stylesheets without it
+        res.headers_mut().set_raw("X-Content-Type-Options", "nosniff");
+        let content_type = self.mime_types.content_type(path);
+        let parts = ranged(&mut res, ranges, found.len, content_type, &etag);
+        res.headers_mut().set(header::ETag(etag));
+        res.headers_mut().set(header::ContentLength(parts.iter().map(Part::len).sum()));
+
+        if *method == Method::Get {
+            res.set_body(match found.contents{
+                Contents::Memory(ref file) => in_memory(file, parts),
+                Contents::Disk(path)       => streamed(&self.streams, path, parts)
+            });
+        }
+        info!("{:>20} - {} - {}", reqaddr, res.status().as_u16(), path_str);
+        res
+    }
+}
+
 impl Service for FileServer{
     type Request  = Request;
     type Response = Response;
@@ -133,53 +676,49 @@ impl Service for FileServer{
     type Future   = ResponseFuture;
 
     fn call(&self, req: Request) -> Self::Future {
-        use hyper::{Method, StatusCode, Body, header};
+        use hyper::{StatusCode, header};
         use std::str::FromStr;
 
         let method  = req.method().clone();
         let uri     = req.uri();
-        let reqpath = Rc::new(String::from(req.path()));
-        let reqaddr = Rc::new(format!("{}", req.remote_addr().unwrap_or(SocketAddr::from_str(&"0.0.0.0:0").unwrap())));
+        let reqpath = String::from(req.path());
+        #[allow(deprecated)] // hyper 0.11 has nothing to replace it with yet
+        let reqaddr = format!("{}", req.remote_addr().unwrap_or(SocketAddr::from_str("0.0.0.0:0").unwrap()));
         if (method != Method::Head &&
             method != Method::Get) ||
             uri.is_absolute()
         {
-            return box future::ok(Response::new().with_status(StatusCode::BadRequest))
+            return Box::new(future::ok(Response::new().with_status(StatusCode::BadRequest)))
         }
         
-        let path = Arc::new(self.decode_path(&req));
-
-        let fetch =
-            self.cache
-                .fetch(path.clone());
-
-        fn to_str<'a>(p: &'a Arc<PathBuf>) -> &'a str{
-            p.to_str().unwrap_or("<nonunicode>")
-        }
-
-        box fetch
-            .then(|r: Result<FileResponse,_>| r.unwrap())
-            .then(move |r: FileResponse|{
-                  let path_str = to_str(&path);
-                  match r{
-                      Err(io) => Ok(io_error(io, path_str, reqpath.borrow(), reqaddr.borrow())),
-                      Ok(smf) => {
-                          // ToDo: etag?
-                          let (mod_time, ref file) = *smf;
-                          let size = file.len() as u64;
-                          let modified = header::HttpDate::from(mod_time);
-                          let mut res = Response::new()
-                              .with_header(header::ContentLength(size))
-                              .with_header(header::LastModified(modified));
-                          
-                          if method == Method::Get {
-                              res.set_body(Body::from(file.clone()));
-                          }
-                          info!("{:>20} - 200 - {}", reqaddr, path_str);
-                          Ok(res)
-                    }
+        let path = self.resolve(&reqpath);
+        let requested = Rc::new(Requested{
+            method,
+            headers: req.headers().clone(),
+            reqpath,
+            reqaddr
+        });
+        let this = self.clone();
+        let stat_path = path.clone();
+        // whether it's a directory is checked on a file thread, the metadata is kept for files
+        Box::new(self.cache
+            .run(move || fs::metadata(stat_path))
+            .then(move |metadata| -> ResponseFuture{
+                let metadata = finished(metadata);
+                let is_dir   = metadata.as_ref().map(|m| m.is_dir()).unwrap_or(false);
+                let target   = this.target(&requested.reqpath, path, is_dir);
+                let missing  = this.missing(&target);
+                match target{
+                    Target::Redirect(location) => {
+                        info!("{:>20} - 301 - {}", requested.reqaddr, location);
+                        Box::new(future::ok(Response::new()
+                            .with_status(StatusCode::MovedPermanently)
+                            .with_header(header::Location::new(location))))
+                    },
+                    Target::File(path)     => this.serve_metadata(requested, path, metadata, missing),
+                    Target::Index(path, _) => this.serve(requested, path, missing)
                 }
-            })
+            }))
     }
 }
 
@@ -187,13 +726,22 @@ impl Service for FileServer{
 pub struct FileServer(Rc<FileServerInternal>);
 
 impl FileServer{
-    pub fn new(root: PathBuf, cache: FileCache)
+    pub fn new(options: FileOptions, cache: FileCache)
         -> FileServer
 {
+        let FileOptions{ root, mime_types, index, listing, fallback } = options;
         FileServer(Rc::new(
             FileServerInternal{
                 root,
                 cache,
+                streams: PoolBuilder::new()
+                    .pool_size(STREAM_THREADS)
+                    .name_prefix("File stream ")
+                    .create(),
+                mime_types: MimeTypes::new(&mime_types),
+                index,
+                listing,
+                fallback
             }
         ))
     }
@@ -205,3 +753,125 @@ impl Deref for FileServer{
         &self.0
     }
 }
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+    use std::env;
+    use std::time::Duration;
+
+    fn headers(raw: &[(&'static str, &str)]) -> Headers{
+        let mut headers = Headers::new();
+        for &(name, value) in raw{
+            headers.set_raw(name, value.to_string());
+        }
+        headers
+    }
+
+    fn modified() -> HttpDate{
+        HttpDate::from(UNIX_EPOCH + Duration::from_secs(1_000_000_000))
+    }
+
+    #[test]
+    fn listings_hide_dotfiles(){
+        let dir = env::temp_dir().join(format!("nodeeditor-listing-{}", ::std::process::id()));
+        fs::create_dir_all(dir.join("sub")).unwrap();
+        fs::create_dir_all(dir.join(".git")).unwrap();
+        fs::write(dir.join("a b.html"), "").unwrap();
+        fs::write(dir.join(".env"), "").unwrap();
+        let html = listing(&dir, "/docs/");
+        fs::remove_dir_all(&dir).unwrap();
+        let html = html.unwrap();
+        assert!(html.contains("<a href=\"a%20b.html\">a b.html</a>"));
+        assert!(html.contains("<a href=\"sub/\">sub/</a>"));
+        assert!(html.contains("<a href=\"../\">"));
+        assert!(!html.contains(".env") && !html.contains(".git"));
+    }
+
+    #[test]
+    fn if_none_match_wins_over_if_modified_since(){
+        let etag  = EntityTag::strong("abc".into());
+        let later = "Sun, 09 Sep 2001 01:46:41 GMT";
+        assert!(not_modified(&headers(&[("If-None-Match", "\"abc\"")]), &etag, modified()));
+        assert!(not_modified(&headers(&[("If-None-Match", "W/\"abc\"")]), &etag, modified()));
+        assert!(not_modified(&headers(&[("If-None-Match", "*")]), &etag, modified()));
+        assert!(not_modified(&headers(&[("If-Modified-Since", later)]), &etag, modified()));
+        assert!(!not_modified(&headers(&[("If-Modified-Since", "Sun, 09 Sep 2001 01:46:39 GMT")]),
+                              &etag, modified()));
+        // a stale tag means the client's copy is out of date whatever the date says
+        assert!(!not_modified(&headers(&[("If-None-Match", "\"old\""), ("If-Modified-Since", later)]),
+                              &etag, modified()));
+        assert!(!not_modified(&headers(&[]), &etag, modified()));
+    }
+
+    #[test]
+    fn if_modified_since_ignores_fractions_of_a_second(){
+        let etag     = EntityTag::strong("abc".into());
+        let modified = HttpDate::from(UNIX_EPOCH + Duration::from_millis(1_000_000_000_500));
+        assert!(not_modified(&headers(&[("If-Modified-Since", "Sun, 09 Sep 2001 01:46:40 GMT")]),
+                             &etag, modified));
+        assert!(!not_modified(&headers(&[("If-Modified-Since", "Sun, 09 Sep 2001 01:46:39 GMT")]),
+                              &etag, modified));
+    }
+
+    fn ranges(raw: &[(&'static str, &str)], len: u64) -> Option<Vec<(u64, u64)>>{
+        let etag = EntityTag::strong("abc".into());
+        match requested_ranges(&headers(raw), &etag, modified(), len){
+            Ranges::Partial(ranges) => Some(ranges),
+            Ranges::Full            => None,
+            Ranges::Unsatisfiable   => Some(vec![])
+        }
+    }
+
+    #[test]
+    fn ranges_are_parsed_and_coalesced(){
+        assert_eq!(ranges(&[], 100), None);
+        assert_eq!(ranges(&[("Range", "bytes=0-9")], 100), Some(vec![(0, 9)]));
+        assert_eq!(ranges(&[("Range", "bytes=-10")], 100), Some(vec![(90, 99)]));
+        assert_eq!(ranges(&[("Range", "bytes=95-")], 100), Some(vec![(95, 99)]));
+        assert_eq!(ranges(&[("Range", "bytes=50-200")], 100), Some(vec![(50, 99)]));
+        assert_eq!(ranges(&[("Range", "bytes=20-29,0-9,10-15,25-40")], 100), Some(vec![(0, 15), (20, 40)]));
+        assert_eq!(ranges(&[("Range", "bytes=100-200")], 100), Some(vec![]));
+    }
+
+    #[test]
+    fn too_many_ranges_get_the_whole_file(){
+        let many: Vec<String> = (0..MAX_RANGES as u64 + 1).map(|i| format!("{}-{}", i * 10, i * 10 + 1)).collect();
+        assert_eq!(ranges(&[("Range", &format!("bytes={}", many.join(",")))], 1000), None);
+        let some: Vec<String> = (0..MAX_RANGES as u64).map(|i| format!("{}-{}", i * 10, i * 10 + 1)).collect();
+        assert_eq!(ranges(&[("Range", &format!("bytes={}", some.join(",")))], 1000).map(|r| r.len()),
+                   Some(MAX_RANGES));
+    }
+
+    #[test]
+    fn if_range_only_matches_the_same_version(){
+        let range = ("Range", "bytes=0-9");
+        assert_eq!(ranges(&[range, ("If-Range", "\"abc\"")], 100), Some(vec![(0, 9)]));
+        assert_eq!(ranges(&[range, ("If-Range", "\"old\"")], 100), None);
+        assert_eq!(ranges(&[range, ("If-Range", "W/\"abc\"")], 100), None);
+        assert_eq!(ranges(&[range, ("If-Range", "Sun, 09 Sep 2001 01:46:40 GMT")], 100), Some(vec![(0, 9)]));
+        assert_eq!(ranges(&[range, ("If-Range", "Sun, 09 Sep 2001 01:46:41 GMT")], 100), None);
+    }
+
+    #[test]
+    fn if_range_dates_ignore_fractions_of_a_second(){
+        let etag     = EntityTag::strong("abc".into());
+        let modified = HttpDate::from(UNIX_EPOCH + Duration::from_millis(1_000_000_000_500));
+        let headers  = headers(&[("Range", "bytes=0-9"), ("If-Range", "Sun, 09 Sep 2001 01:46:40 GMT")]);
+        match requested_ranges(&headers, &etag, modified, 100){
+            Ranges::Partial(ranges) => assert_eq!(ranges, vec![(0, 9)]),
+            _ => panic!("expected the range to be honoured")
+        }
+    }
+
+    #[test]
+    fn negotiate_prefers_brotli_when_as_welcome(){
+        let encoding = |accept: &str| negotiate(&headers(&[("Accept-Encoding", accept)]));
+        assert_eq!(negotiate(&headers(&[])), Encoding::Identity);
+        assert_eq!(encoding("gzip, br"), Encoding::Brotli);
+        assert_eq!(encoding("gzip"), Encoding::Gzip);
+        assert_eq!(encoding("br;q=0.5, gzip"), Encoding::Gzip);
+        assert_eq!(encoding("br;q=0, gzip;q=0"), Encoding::Identity);
+        assert_eq!(encoding("deflate"), Encoding::Identity);
+    }
+}
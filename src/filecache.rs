@@ -1,5 +1,5 @@
-use std::sync::Arc;
-use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::collections::{BTreeMap, HashMap};
 use std::thread;
 use std::thread::{JoinHandle};
 use std::io;
@@ -13,95 +13,271 @@ use futures::sync::oneshot::{Sender as OneshotSender,
                              Canceled};
 
 use rebuilder::{InvalidationEvent, InvalidationPath, InvalidationReceiverChain, RepeatAfter};
-use filethread::{SharedMemoryFile, FileThreadPool, RequestPath};
+use filethread::{SharedMemoryFile, FileThreadPool, RequestKey, Encoding, ENCODINGS};
 
-type Request     = (RequestPath, OneshotSender<Response>);
+type Request     = (RequestKey, OneshotSender<Response>);
 type Response    = io::Result<SharedMemoryFile>;
 
+/// Bounds on what the FileCache keeps in memory. The least recently used files are dropped to
+/// stay within them.
+#[derive(Copy, Clone, Debug)]
+pub struct CacheLimits{
+    pub max_bytes:     usize,
+    pub max_entries:   usize,
+    /// Files bigger than this are streamed from disk for every request instead of being cached.
+    pub max_file_size: usize
+}
+
+impl CacheLimits{
+    /// Whether a file of `size` bytes is small enough to be cached.
+    pub fn keeps(&self, size: u64) -> bool{
+        size <= self.max_file_size as u64 && size <= self.max_bytes as u64 && self.max_entries > 0
+    }
+}
+
+/// What the FileCache has done since it started, and what it holds now.
+#[derive(Copy, Clone, Debug, Default)]
+pub struct CacheStats{
+    pub hits:      u64,
+    pub misses:    u64,
+    /// Files dropped to make room, not counting those dropped because they changed.
+    pub evictions: u64,
+    pub entries:   usize,
+    pub bytes:     usize
+}
+
+/// Reads the CacheStats of a FileCache without keeping the cache itself alive.
+#[derive(Clone)]
+pub struct SharedStats(Arc<Mutex<CacheStats>>);
+
+impl SharedStats{
+    pub fn get(&self) -> CacheStats{
+        *self.0.lock().unwrap()
+    }
+}
+
 #[derive(Clone)]
 pub struct FileCache(Arc<FileCacheThread>);
 
 impl FileCache{
-    pub fn new(n_threads: usize,
+    pub fn new(n_threads: usize, limits: CacheLimits,
                invalidation_chain: InvalidationReceiverChain) -> FileCache{
         let (req_out, req_in) = bounded_channel(n_threads); // enough channel space to fill all threads
+        let stats = Arc::new(Mutex::new(CacheStats::default()));
+        let thread_stats = stats.clone();
+        let file_threads = Arc::new(FileThreadPool::new(n_threads, limits.max_file_size as u64));
+        let thread_files = file_threads.clone();
         let handle = thread::Builder::new()
             .name("filecache".into())
             .spawn(move || {
-                let state = FileCacheState::new(n_threads, invalidation_chain, req_in);
+                let state = FileCacheState::new(thread_files, limits, thread_stats,
+                                                invalidation_chain, req_in);
                 state.run()
             }).unwrap();
 
         FileCache(Arc::new(
             FileCacheThread{
                 req_out,
+                stats,
+                limits,
+                file_threads,
                 handle
             }
         ))
     }
-    pub fn fetch(&self, path: RequestPath)
+    pub fn stats(&self) -> SharedStats{
+        SharedStats(self.0.stats.clone())
+    }
+    /// Whether a file of `size` bytes is small enough to be cached. Bigger files have to be
+    /// streamed from disk instead of fetched.
+    pub fn keeps(&self, size: u64) -> bool{
+        self.0.limits.keeps(size)
+    }
+    /// Runs `job` on a file thread without going through the cache, for filesystem calls other
+    /// than reading a file.
+    pub fn run<F, T>(&self, job: F) -> impl Future<Item=T, Error=Canceled>
+        where F: FnOnce() -> T + Send + 'static,
+              T: Send + 'static
+    {
+        self.0.file_threads.run(job)
+    }
+    /// Encoded variants are cached alongside the original and invalidated with it.
+    pub fn fetch(&self, key: RequestKey)
         -> impl Future<Item  = Response,
                        Error = Canceled>
     {
-        self.0.fetch(path)
+        self.0.fetch(key)
     }
 }
 
 struct FileCacheThread{
-    req_out: BoundedSender<Request>,
-    handle:  JoinHandle<()>
+    req_out:      BoundedSender<Request>,
+    stats:        Arc<Mutex<CacheStats>>,
+    limits:       CacheLimits,
+    file_threads: Arc<FileThreadPool>,
+    handle:       JoinHandle<()>
 }
 
 impl FileCacheThread{
-    pub fn fetch(&self, path: RequestPath)
+    pub fn fetch(&self, key: RequestKey)
         -> impl Future<Item  = Response,
                        Error = Canceled>
     {
         let (resp_out, resp_in) = oneshot_channel();
         self.req_out
             .clone() // either I clone or I Mutex, I think this is better
-            .send((path, resp_out))
-            .map_err(|e| panic!(e))
+            .send((key, resp_out))
+            .map_err(|e| panic!("{:?}", e))
             .and_then(|_| resp_in)
     }
 }
 
-type CacheStore = HashMap<RequestPath, SharedMemoryFile>;
+// files with when they were last used, and the keys ordered by that so the least recently used
+// one is first
+struct CacheStore{
+    entries: HashMap<RequestKey, (SharedMemoryFile, u64)>,
+    recency: BTreeMap<u64, RequestKey>,
+    tick:    u64,
+    bytes:   usize,
+    limits:  CacheLimits,
+    stats:   Arc<Mutex<CacheStats>>
+}
+
+impl CacheStore{
+    fn new(limits: CacheLimits, stats: Arc<Mutex<CacheStats>>) -> CacheStore{
+        CacheStore{
+            entries: HashMap::new(),
+            recency: BTreeMap::new(),
+            tick:    0,
+            bytes:   0,
+            limits,
+            stats
+        }
+    }
+
+    fn contains_key(&self, key: &RequestKey) -> bool{
+        self.entries.contains_key(key)
+    }
+
+    // marks the file as the most recently used
+    fn get(&mut self, key: &RequestKey) -> Option<SharedMemoryFile>{
+        self.tick += 1;
+        let tick = self.tick;
+        let (value, last_used) = self.entries
+            .get_mut(key)
+            .map(|entry| (entry.0.clone(), ::std::mem::replace(&mut entry.1, tick)))?;
+        if let Some(key) = self.recency.remove(&last_used){
+            self.recency.insert(tick, key);
+        }
+        Some(value)
+    }
+
+    // returns false if the file is too big to be cached
+    fn insert(&mut self, key: RequestKey, value: SharedMemoryFile) -> bool{
+        self.remove(&key);
+        let size = value.1.len();
+        if !self.limits.keeps(size as u64){
+            return false;
+        }
+        self.tick += 1;
+        self.recency.insert(self.tick, key.clone());
+        self.entries.insert(key, (value, self.tick));
+        self.bytes += size;
+        self.shrink();
+        true
+    }
+
+    fn remove(&mut self, key: &RequestKey) -> Option<SharedMemoryFile>{
+        let (value, last_used) = self.entries.remove(key)?;
+        self.recency.remove(&last_used);
+        self.bytes -= value.1.len();
+        self.update_stats();
+        Some(value)
+    }
+
+    // evicts the least recently used files until the cache is within its limits again
+    fn shrink(&mut self){
+        while self.bytes > self.limits.max_bytes || self.entries.len() > self.limits.max_entries{
+            let oldest = match self.recency.keys().next(){
+                Some(&tick) => tick,
+                None        => break
+            };
+            if let Some(key) = self.recency.remove(&oldest){
+                trace!("Evicting {}", to_str(&key));
+                if let Some((value, _)) = self.entries.remove(&key){
+                    self.bytes -= value.1.len();
+                }
+                self.stats.lock().unwrap().evictions += 1;
+            }
+        }
+        self.update_stats();
+    }
+
+    fn update_stats(&self){
+        let mut stats = self.stats.lock().unwrap();
+        stats.entries = self.entries.len();
+        stats.bytes   = self.bytes;
+    }
+
+    fn record(&self, hit: bool){
+        let mut stats = self.stats.lock().unwrap();
+        if hit{
+            stats.hits += 1;
+        }
+        else{
+            stats.misses += 1;
+        }
+    }
+}
+
 pub struct FileCacheState{
     req_in:             BoundedReceiver<Request>,
     store:              CacheStore,
     invalidation_chain: InvalidationReceiverChain,
-    file_threads:       FileThreadPool
+    file_threads:       Arc<FileThreadPool>
+}
+
+fn to_str(key: &RequestKey) -> &str{
+    key.0.to_str().unwrap_or("<nonunicode>")
 }
 
-fn to_str<'a>(rp: &'a RequestPath) -> &'a str{
-    rp.to_str().unwrap_or("<nonunicode>")
+// drops every encoding of `path`, and the variant `path` is a precompressed version of if it's one
+fn evict(store: &mut CacheStore, path: &InvalidationPath){
+    for &encoding in ENCODINGS.iter(){
+        store.remove(&(path.clone(), encoding));
+        if let Some(original) = encoding.original(path){
+            store.remove(&(Arc::new(original), encoding));
+        }
+    }
 }
 
 // XXX: currently we actually block on file requests, making the io threads pointless.
 // Need to add a mechanism for deferred cache stores where the request future is passed directly to
 // the client.
 impl FileCacheState{
-    pub fn new(n_threads: usize,
+    pub fn new(file_threads: Arc<FileThreadPool>,
+               limits: CacheLimits,
+               stats: Arc<Mutex<CacheStats>>,
                invalidation_chain: InvalidationReceiverChain,
                req_in: BoundedReceiver<Request>) -> FileCacheState{
         FileCacheState{
             req_in,
             invalidation_chain,
-            store: HashMap::new(),
-            file_threads: FileThreadPool::new(n_threads)
+            store: CacheStore::new(limits, stats),
+            file_threads
         }
     }
 
-    pub fn invalidate(store: &mut CacheStore, file: &FileThreadPool, ev: InvalidationEvent){
+    fn invalidate(store: &mut CacheStore, file: &FileThreadPool, ev: InvalidationEvent){
         let read = |store: &mut CacheStore, path: InvalidationPath| -> bool {
-            store.remove(&path);
+            let key = (path, Encoding::Identity);
+            store.remove(&key);
             let r: Response =
-                file.fetch(path.clone())
-                    .map_err(|e| panic!(e))
+                file.fetch(key.clone())
+                    .map_err(|e| panic!("{:?}", e))
                     .wait().unwrap();
             if let Ok(smf) = r{
-                store.insert(path, smf);
+                store.insert(key, smf);
                 true
             }
             else{
@@ -114,75 +290,89 @@ impl FileCacheState{
             // File modification is frequently reported as creation so just treat it the same here Added(_) => {}, // don't eagerly cache yet
             Removed(path) => {
                 trace!("Removing invalidated file {:?}", path);
-                store.remove(&path);
+                evict(store, &path);
             },
             Added(path) | Modified(path) => {
-                if !store.contains_key(&path){
+                let cached = store.contains_key(&(path.clone(), Encoding::Identity));
+                // encoded variants are only made again once they're asked for
+                evict(store, &path);
+                if !cached{
                     return; // don't eagerly cache uncached files
                 }
                 trace!("Updating invalidated file {:?}", path);
                 if !read(store, path.clone()){
                     trace!("Modified file {:?} now unreadable, removing from cache.", path);
-                    store.remove(&path);
                 }
             }
             Renamed(old, new) => {
                 trace!("Renaming {:?} to {:?}", old, new);
-                if let Some(old) = store.remove(&old){
-                    store.insert(new, old);
-                }
-                else{
-                    if !read(store, new.clone()){
-                        trace!("Rename target {:?} not readable, remaining uncached.", new);
+                evict(store, &new);
+                let mut moved = false;
+                for &encoding in ENCODINGS.iter(){
+                    if let Some(v) = store.remove(&(old.clone(), encoding)){
+                        store.insert((new.clone(), encoding), v);
+                        moved = true;
                     }
                 }
+                evict(store, &old);
+                if !moved && !read(store, new.clone()){
+                    trace!("Rename target {:?} not readable, remaining uncached.", new);
+                }
             }
         }
     }
 
-    pub fn get(store: &CacheStore, key: &RequestPath) -> Option<SharedMemoryFile>{
-        if let Some(v) = store.get(key){
+    fn get(store: &mut CacheStore, key: &RequestKey) -> Option<SharedMemoryFile>{
+        let cached = store.get(key);
+        if cached.is_some(){
             trace!("Cache hit {}", to_str(key));
-            Some(v.clone())
         }
         else{
             trace!("Cache miss {}", to_str(key));
-            None
         }
+        store.record(cached.is_some());
+        cached
     }
 
-    pub fn insert(store: &mut CacheStore, key: RequestPath, value: SharedMemoryFile){
-        trace!("Caching {}", to_str(&key));
-        store.insert(key, value); // we don't care if the file was already cached
+    fn insert(store: &mut CacheStore, key: RequestKey, value: SharedMemoryFile){
+        let size = value.1.len();
+        // we don't care if the file was already cached
+        if store.insert(key.clone(), value){
+            trace!("Caching {}", to_str(&key));
+        }
+        else{
+            trace!("Not caching {}, {} bytes is too big", to_str(&key), size);
+        }
     }
 
     pub fn run(mut self){
         // I know. This is awful. I did whatever I could to make it compile.
 
-        let (mut store, file, chain, req_in) =
+        let (store, file, chain, req_in) =
             (&mut self.store, self.file_threads, self.invalidation_chain, self.req_in);
  
         let first = |a: RepeatAfter<InvalidationEvent>, store: &mut CacheStore, file: &FileThreadPool|{
             Self::invalidate(store, file, a.get().clone());
-            a.repeat_block().unwrap();
+            if a.repeat_block().is_err(){
+                debug!("Next invalidation receiver has stopped, not repeating");
+            }
         };
 
         let second = |req: Request, store: &mut CacheStore, file: &FileThreadPool|{
-            let (path, resp_out) = req;
-            let (cached, store) =
-                (Self::get(&store, &path), store);
+            let (key, resp_out) = req;
+            let cached = Self::get(store, &key);
             if let Some(hit) = cached{
-                resp_out.send(Ok(hit))
-                        .unwrap();
+                // the requester may have gone away, which is fine
+                let _ = resp_out.send(Ok(hit));
             }
             else{
-                file.fetch(path.clone())
-                    .map_err(|e| panic!(e))
+                file.fetch(key.clone())
+                    .map_err(|e| panic!("{:?}", e))
                     .map(|r|{
                         if let Ok(ref smf) = r{
-                            Self::insert(store, path, smf.clone());
+                            Self::insert(store, key, smf.clone());
                         }
-                        resp_out.send(r).unwrap();
+                        let _ = resp_out.send(r);
                      })
                     .wait().unwrap()
             }
@@ -191,22 +381,90 @@ impl FileCacheState{
         enum FS<F,S>{
             First(F),
             Second(S)
-        };
+        }
 
         chain
             .recv_repeat_after()
-            .map(|f| FS::First(f)) 
+            .map(FS::First)
             .map_err(|_| ())
-            .select(req_in.map(|s| FS::Second(s))
+            .select(req_in.map(FS::Second)
                           .map_err(|_| ()))
-            .map(|r| { match r{
-                FS::First(a)    => first(a, &mut store, &file),
-                FS::Second(req) => second(req, &mut store, &file)
+            .for_each(|r| { match r{
+                FS::First(a)    => first(a, store, &file),
+                FS::Second(req) => second(req, store, &file)
             }
+            Ok(())
         }).wait()
-          .last()
-          .unwrap()
-          .unwrap();
+          .unwrap_or_else(|_| error!("Invalidations or requests failed"));
+        debug!("Invalidations and requests stopped, stopping. {:?}", *store.stats.lock().unwrap());
     }
 }
 
+#[cfg(test)]
+mod tests{
+    use super::*;
+    use std::path::PathBuf;
+    use std::time::SystemTime;
+    use bytes::Bytes;
+
+    fn store(max_bytes: usize, max_entries: usize, max_file_size: usize) -> CacheStore{
+        CacheStore::new(CacheLimits{ max_bytes, max_entries, max_file_size }, Arc::default())
+    }
+
+    fn key(name: &str) -> RequestKey{
+        (Arc::new(PathBuf::from(name)), Encoding::Identity)
+    }
+
+    fn file(size: usize) -> SharedMemoryFile{
+        Arc::new((SystemTime::now(), Bytes::from(vec![0; size]), "etag".into()))
+    }
+
+    #[test]
+    fn evicts_the_least_recently_used(){
+        let mut store = store(30, 10, 30);
+        assert!(store.insert(key("a"), file(10)));
+        assert!(store.insert(key("b"), file(10)));
+        assert!(store.insert(key("c"), file(10)));
+        assert!(store.get(&key("a")).is_some());
+        assert!(store.insert(key("d"), file(10)));
+        assert!(store.contains_key(&key("a")));
+        assert!(!store.contains_key(&key("b")));
+        assert!(store.contains_key(&key("c")));
+        assert!(store.contains_key(&key("d")));
+
+        let stats = store.stats.lock().unwrap();
+        assert_eq!((stats.entries, stats.bytes, stats.evictions), (3, 30, 1));
+    }
+
+    #[test]
+    fn stays_within_the_entry_limit(){
+        let mut store = store(1000, 2, 1000);
+        for name in &["a", "b", "c", "d"]{
+            store.insert(key(name), file(1));
+        }
+        assert_eq!(store.entries.len(), 2);
+        assert!(store.contains_key(&key("c")) && store.contains_key(&key("d")));
+    }
+
+    #[test]
+    fn refuses_files_over_the_limits(){
+        let mut store = store(100, 10, 50);
+        assert!(!store.insert(key("big"), file(51)));
+        assert!(store.insert(key("fits"), file(50)));
+        assert!(!store.contains_key(&key("big")));
+        assert!(!CacheLimits{ max_bytes: 10, max_entries: 10, max_file_size: 100 }.keeps(11));
+        assert!(!CacheLimits{ max_bytes: 10, max_entries: 0, max_file_size: 10 }.keeps(1));
+    }
+
+    #[test]
+    fn replacing_and_removing_keep_the_byte_count(){
+        let mut store = store(100, 10, 100);
+        store.insert(key("a"), file(40));
+        store.insert(key("a"), file(20));
+        assert_eq!(store.bytes, 20);
+        assert!(store.remove(&key("a")).is_some());
+        assert!(store.remove(&key("a")).is_none());
+        assert_eq!(store.bytes, 0);
+        assert!(store.recency.is_empty());
+    }
+}
@@ -1,12 +1,20 @@
 use std::io;
-use std::io::Read;
-use std::path::{PathBuf};
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
 use std::sync::{Arc, atomic::AtomicUsize};
+use std::fs;
 use std::fs::File;
 use std::time::SystemTime;
 use std::thread;
 use std::thread::JoinHandle;
 use std::borrow::Borrow;
+use std::collections::hash_map::DefaultHasher;
+use std::hash::Hasher;
+
+use bytes::Bytes;
+use flate2::Compression;
+use flate2::write::GzEncoder;
+use brotli;
 
 use futures::{Future, Sink, Stream};
 use futures::sync::mpsc::channel as bounded_channel; // rename this because defaulting to bounded is dumb
@@ -16,36 +24,160 @@ use futures::sync::oneshot::{Sender as OneshotSender,
                              channel as oneshot_channel,
                              Canceled};
 
-pub type InMemoryFile = (SystemTime, Vec<u8>);
+/// Modification time, contents and a strong ETag derived from the contents.
+pub type InMemoryFile = (SystemTime, Bytes, String);
 pub type SharedMemoryFile = Arc<InMemoryFile>;
 
+const GZIP_LEVEL:     u32 = 6;
+const BROTLI_QUALITY: u32 = 5;
+const BROTLI_LGWIN:   u32 = 22;
+
+/// How a file's contents are encoded.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum Encoding{
+    Identity,
+    Gzip,
+    Brotli
+}
+
+pub static ENCODINGS: [Encoding; 3] = [Encoding::Identity, Encoding::Gzip, Encoding::Brotli];
+
+impl Encoding{
+    /// Name used in Content-Encoding.
+    pub fn name(self) -> &'static str{
+        match self{
+            Encoding::Identity => "identity",
+            Encoding::Gzip     => "gzip",
+            Encoding::Brotli   => "br"
+        }
+    }
+
+    // precompressed files sit next to the original with this appended
+    fn extension(self) -> &'static str{
+        match self{
+            Encoding::Identity => "",
+            Encoding::Gzip     => ".gz",
+            Encoding::Brotli   => ".br"
+        }
+    }
+
+    /// Where a precompressed version of `path` would be.
+    pub fn sibling(self, path: &Path) -> PathBuf{
+        let mut sibling = path.as_os_str().to_owned();
+        sibling.push(self.extension());
+        sibling.into()
+    }
+
+    /// The file `path` is a precompressed version of, if it is one.
+    pub fn original(self, path: &Path) -> Option<PathBuf>{
+        let ext  = self.extension();
+        let path = path.to_str()?;
+        if ext.is_empty() || !path.ends_with(ext){
+            return None;
+        }
+        Some(path[..path.len() - ext.len()].into())
+    }
+
+    // runs on a file thread while a request waits, so it uses cheap settings. Precompressed
+    // siblings are the place for maximum compression
+    fn compress(self, contents: &[u8]) -> io::Result<Vec<u8>>{
+        match self{
+            Encoding::Identity => Ok(contents.to_vec()),
+            Encoding::Gzip     => {
+                let mut encoder = GzEncoder::new(Vec::new(), Compression::new(GZIP_LEVEL));
+                encoder.write_all(contents)?;
+                encoder.finish()
+            },
+            Encoding::Brotli   => {
+                let mut compressed = Vec::new();
+                { // the stream is finished when the writer is dropped
+                    let mut encoder = brotli::CompressorWriter::new(&mut compressed, 4096,
+                                                                    BROTLI_QUALITY, BROTLI_LGWIN);
+                    encoder.write_all(contents)?;
+                }
+                Ok(compressed)
+            }
+        }
+    }
+}
+
 pub type RequestPath = Arc<PathBuf>;
-    type Request     = (RequestPath, OneshotSender<Response>);
+/// A file and the encoding it's wanted in.
+pub type RequestKey  = (RequestPath, Encoding);
 pub type Response    = io::Result<SharedMemoryFile>;
 // pub type FileThreadResult = OneshotReceiver<Response>; <-- File thread responses look like this
 
-struct FileThreadState(BoundedReceiver<Request>);
+pub enum Request{
+    Fetch(RequestKey, OneshotSender<Response>),
+    // any other blocking filesystem work, like checking whether a path is a directory
+    Run(Box<dyn FnOnce() + Send>)
+}
+
+struct FileThreadState{
+    req_in:   BoundedReceiver<Request>,
+    max_size: u64
+}
+
+// only has to tell versions of the same file apart while the server runs
+fn etag(contents: &[u8]) -> String{
+    let mut hasher = DefaultHasher::new();
+    hasher.write(contents);
+    format!("{:016x}-{:x}", hasher.finish(), contents.len())
+}
+
+// files bigger than `max_size` are refused, those are streamed from disk instead
+fn read(path: &Path, max_size: u64) -> io::Result<(SystemTime, Vec<u8>)>{
+    let mut file = File::open(path)?;
+    let metadata = file.metadata()?;
+    let len      = metadata.len();
+    let mod_date = metadata.modified()?;
+    if len > max_size{
+        return Err(io::Error::other(format!("{:?} is too big to read into memory", path)));
+    }
+    let mut buf  = Vec::with_capacity(len as usize);
+    file.read_to_end(&mut buf)?;
+    if buf.len() as u64 != len{
+        return Err(io::Error::new(io::ErrorKind::UnexpectedEof,
+                                  format!("{:?} changed size while it was read", path)));
+    }
+    trace!("Read file {}", path.to_str().unwrap_or("<nonunicode>"));
+
+    Ok((mod_date, buf))
+}
 
 impl FileThreadState{
-    fn get_file(path: &PathBuf) -> Response{
-        trace!("Fetching {}", path.to_str().unwrap());
-        let mut file = File::open(path)?;
-        let metadata = file.metadata()?;
-        let len      = metadata.len() as usize; // on 32bit systems we wouldn't be able to load >2^32 sized files anyway
-        let mod_date = metadata.modified()?;
-        let mut buf  = Vec::with_capacity(len); // XXX: this will explode on huge files
-        let read     = file.read_to_end(&mut buf)?;
-        assert!(read == len);
-        trace!("Read file {}", path.to_str().unwrap());
-
-        Ok(Arc::new((mod_date, buf)))
+    // encoded files come from a precompressed sibling if there's an up to date one, otherwise the
+    // file is compressed here
+    fn get_file(path: &PathBuf, encoding: Encoding, max_size: u64) -> Response{
+        trace!("Fetching {} as {}", path.to_str().unwrap_or("<nonunicode>"), encoding.name());
+        let (mod_date, buf) = match encoding{
+            Encoding::Identity => read(path, max_size)?,
+            _ => {
+                let mod_date = fs::metadata(path)?.modified()?;
+                match read(&encoding.sibling(path), max_size){
+                    Ok((sibling_date, buf)) if sibling_date >= mod_date => (mod_date, buf),
+                    _ => {
+                        let (mod_date, buf) = read(path, max_size)?;
+                        (mod_date, encoding.compress(&buf)?)
+                    }
+                }
+            }
+        };
+
+        let etag     = etag(&buf);
+        Ok(Arc::new((mod_date, Bytes::from(buf), etag)))
     }
     pub fn run(self){
-        let task = self.0.for_each(
+        let max_size = self.max_size;
+        let task = self.req_in.for_each(
             move |req| {
-                let (path, resp) = req;
-                resp.send(Self::get_file(path.borrow()))
-                    .unwrap();
+                match req{
+                    Request::Fetch((path, encoding), resp) => {
+                        // the cache may have stopped waiting for it, which is fine
+                        let _ = resp.send(Self::get_file(path.borrow(), encoding, max_size));
+                    },
+                    Request::Run(job) => job()
+                }
                 Ok(())
             }
         );
@@ -55,13 +187,13 @@ impl FileThreadState{
 }
 
 impl FileThread{
-    pub fn new(thread_number: usize) -> FileThread{
+    pub fn new(thread_number: usize, max_size: u64) -> FileThread{
         // bounded_channel ensures that each thread has only one outstanding request at a time.
         let (req_out, req_in)   = bounded_channel(1);
         let handle = thread::Builder::new()
-            .name(format!("File IO {}", thread_number).into())
+            .name(format!("File IO {}", thread_number))
             .spawn(move ||{
-            let state = FileThreadState(req_in);
+            let state = FileThreadState{ req_in, max_size };
             state.run();
         }).unwrap();
         
@@ -127,29 +259,45 @@ pub struct FileThreadPool{
 }
 
 impl FileThreadPool{
-    pub fn new(n_threads: usize) -> FileThreadPool {
-        let threads = (0..n_threads).map(FileThread::new).collect();
+    /// Threads that read files of up to `max_size` bytes into memory.
+    pub fn new(n_threads: usize, max_size: u64) -> FileThreadPool {
+        let threads = (0..n_threads).map(|i| FileThread::new(i, max_size)).collect();
         FileThreadPool{
             threads,
             scheduler: RoundRobin::new(n_threads)
         }
     }
 
-    pub fn fetch(&self, path: RequestPath)
-        -> impl Future<Item=Response, Error=Canceled>
-    {
+    fn send(&self, req: Request) -> impl Future<Item=(), Error=()>{
         // access the IO thread pool in a round-robin fashion
         let current_thread = self.scheduler.get_next();
-        let req_out = &self.threads[current_thread].req_out;
-       
-        let (file_out, file_in) = oneshot_channel();
-        req_out
+        self.threads[current_thread]
+            .req_out
             .clone() // easier to clone than to &mut self
-            .send((path, file_out))
+            .send(req)
+            .map(|_| ())
             .map_err(|_| panic!())
-            .and_then(move |_|{
-                file_in
-            })
+    }
+
+    pub fn fetch(&self, key: RequestKey)
+        -> impl Future<Item=Response, Error=Canceled>
+    {
+        let (file_out, file_in) = oneshot_channel();
+        self.send(Request::Fetch(key, file_out))
+            .then(move |_| file_in)
+    }
+
+    /// Runs `job` on one of the threads, so blocking filesystem calls stay off the event loop.
+    pub fn run<F, T>(&self, job: F) -> impl Future<Item=T, Error=Canceled>
+        where F: FnOnce() -> T + Send + 'static,
+              T: Send + 'static
+    {
+        let (result_out, result_in) = oneshot_channel();
+        self.send(Request::Run(Box::new(move ||{
+                // the requester may have gone away, which is fine
+                let _ = result_out.send(job());
+            })))
+            .then(move |_| result_in)
     }
 }
 
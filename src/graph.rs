@@ -1,18 +1,33 @@
-use std::collections::{BTreeMap, BTreeSet};
-use std::cell::{Cell, RefCell};
-use std::rc::Rc;
+use std::collections::{BTreeMap, BTreeSet, VecDeque};
+use std::sync::{Arc, Mutex, RwLock};
 use std::fmt;
 
-use ws::{Message as WsMessage, Error as WsError, Sender as WsSender};
+use serde::{Serialize, Serializer, Deserialize, Deserializer};
+use serde::de;
+use serde_json;
+use rmp_serde;
+use ws::{Message as WsMessage, Error as WsError, ErrorKind as WsErrorKind, Sender as WsSender};
+
+use persist::Persister;
+use eval::Evaluator;
+use wire;
 
 pub type GraphId    = u32;
 pub type NodeId     = String;
 pub type PortId     = String;
 pub type DataId     = String;
+pub type DataType   = String;
+pub type RequestId  = u64;
+pub type Seq        = u64;
+
+/// Location of a node in the tree: the NodeId of each ancestor followed by the node's own NodeId.
+/// Children of a Container have no ids, so they're addressed by their index written as a string.
+pub type NodePath   = Vec<NodeId>;
 
 #[derive(Debug)]
 pub enum PossibleErr{
-    Ws(WsError),
+    // boxed, ws::Error is large enough to bloat every Result
+    Ws(Box<WsError>),
     String(String),
     None
 }
@@ -27,26 +42,40 @@ impl fmt::Display for PossibleErr {
         }
     }
 }
-impl Into<PossibleErr> for String{
-    fn into(self) -> PossibleErr{
-        PossibleErr::String(self)
-    }
-}
-impl Into<PossibleErr> for ::std::option::NoneError{
-    fn into(self) -> PossibleErr{
-        PossibleErr::None
+impl From<String> for PossibleErr{
+    fn from(s: String) -> PossibleErr{
+        PossibleErr::String(s)
     }
 }
 
 impl From<WsError> for PossibleErr{
     fn from(g: WsError) -> PossibleErr{
-        PossibleErr::Ws(g)
+        PossibleErr::Ws(Box::new(g))
     }
 }
 
 type Result<T> = ::std::result::Result<T, PossibleErr>;
 
 
+/// What a port carries. Untyped ports link to anything and unlimited ports take any number of
+/// links.
+#[derive(Clone, Default, Debug, PartialEq, Deserialize, Serialize)]
+pub struct PortSpec{
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub data_type: Option<DataType>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_links: Option<usize>
+}
+
+impl PortSpec{
+    pub fn compatible(&self, other: &PortSpec) -> bool{
+        match (&self.data_type, &other.data_type){
+            (Some(a), Some(b)) => a == b,
+            _ => true
+        }
+    }
+}
+
 #[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
 #[serde(tag="type")]
 pub enum Node{
@@ -55,9 +84,215 @@ pub enum Node{
     Container { nodes: Vec<Node> },
     Knob      { data: DataId },
     Button    { data: DataId },
-    BiPort,
-    InPort,
-    OutPort
+    BiPort(PortSpec),
+    InPort(PortSpec),
+    OutPort(PortSpec)
+}
+
+impl Node{
+    pub fn is_port(&self) -> bool{
+        use self::Node::*;
+        matches!(*self, BiPort(_) | InPort(_) | OutPort(_))
+    }
+
+    pub fn port_spec(&self) -> Option<&PortSpec>{
+        use self::Node::*;
+        match *self{
+            BiPort(ref spec) | InPort(ref spec) | OutPort(ref spec) => Some(spec),
+            _ => None
+        }
+    }
+
+    pub fn child(&self, id: &NodeId) -> Option<&Node>{
+        use self::Node::*;
+        match *self{
+            Labelled { ref nodes, .. } => nodes.get(id),
+            Container{ ref nodes }     => id.parse::<usize>().ok().and_then(move |i| nodes.get(i)),
+            _ => None
+        }
+    }
+
+    pub fn child_mut(&mut self, id: &NodeId) -> Option<&mut Node>{
+        use self::Node::*;
+        match *self{
+            Labelled { ref mut nodes, .. } => nodes.get_mut(id),
+            Container{ ref mut nodes }     => id.parse::<usize>().ok().and_then(move |i| nodes.get_mut(i)),
+            _ => None
+        }
+    }
+
+    fn insert_child(&mut self, id: &NodeId, node: Node) -> Result<()>{
+        use self::Node::*;
+        match *self{
+            Labelled{ ref mut nodes, .. } => {
+                if nodes.contains_key(id){
+                    return Err(format!("Node {} already exists", id).into())
+                }
+                nodes.insert(id.clone(), node);
+                Ok(())
+            },
+            Container{ ref mut nodes } =>
+                match id.parse::<usize>(){
+                    Ok(i) if i <= nodes.len() => {
+                        nodes.insert(i, node);
+                        Ok(())
+                    },
+                    _ => Err(format!("Container has no index {}", id).into())
+                },
+            _ => Err(format!("Can't add {} to a node without children", id).into())
+        }
+    }
+
+    fn remove_child(&mut self, id: &NodeId) -> Option<Node>{
+        use self::Node::*;
+        match *self{
+            Labelled { ref mut nodes, .. } => nodes.remove(id),
+            Container{ ref mut nodes }     =>
+                match id.parse::<usize>(){
+                    Ok(i) if i < nodes.len() => Some(nodes.remove(i)),
+                    _ => None
+                },
+            _ => None
+        }
+    }
+
+    /// Collects every port in this subtree, including the node itself, keyed by PortId. `id` is
+    /// the node's PortId, see `GraphData::port_id`.
+    pub fn collect_ports<'a>(&'a self, id: &NodeId, out: &mut BTreeMap<PortId, &'a Node>){
+        use self::Node::*;
+        match *self{
+            BiPort(_) | InPort(_) | OutPort(_) => {
+                out.insert(id.clone(), self);
+            },
+            Labelled{ ref nodes, .. } =>
+                for (child_id, child) in nodes.iter(){
+                    child.collect_ports(child_id, out);
+                },
+            Container{ ref nodes } =>
+                for (i, child) in nodes.iter().enumerate(){
+                    child.collect_ports(&container_child_id(id, i), out);
+                },
+            _ => {}
+        }
+    }
+
+    /// Collects the PortIds of every port in this subtree, including the node itself.
+    pub fn ports(&self, id: &NodeId, out: &mut BTreeSet<PortId>){
+        let mut ports = BTreeMap::new();
+        self.collect_ports(id, &mut ports);
+        out.extend(ports.into_keys());
+    }
+
+    fn has_ports(&self) -> bool{
+        let mut ports = BTreeMap::new();
+        self.collect_ports(&NodeId::new(), &mut ports);
+        !ports.is_empty()
+    }
+}
+
+// Container children are named by their index, which is qualified with the Container's PortId so
+// that the children of different Containers don't share PortIds.
+fn container_child_id(container: &PortId, index: usize) -> PortId{
+    format!("{}/{}", container, index)
+}
+
+/// Whether data may flow from `source` to `target`.
+fn can_link(source: &Node, target: &Node) -> bool{
+    use self::Node::*;
+    matches!((source, target),
+             (&OutPort(_), &InPort(_)) | (&OutPort(_), &BiPort(_)) |
+             (&BiPort(_),  &InPort(_)) | (&BiPort(_),  &BiPort(_)))
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum LinkError{
+    UnknownPort(PortId),
+    Direction{ source: PortId, target: PortId },
+    Incompatible{ source: PortId, target: PortId },
+    Full{ port: PortId, max_links: usize }
+}
+
+impl fmt::Display for LinkError{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result{
+        use self::LinkError::*;
+        match *self{
+            UnknownPort(ref port) =>
+                write!(f, "{} is not a port", port),
+            Direction{ ref source, ref target } =>
+                write!(f, "Can't link {} -> {}, ports have the wrong direction", source, target),
+            Incompatible{ ref source, ref target } =>
+                write!(f, "Can't link {} -> {}, ports have incompatible types", source, target),
+            Full{ ref port, max_links } =>
+                write!(f, "{} already has its maximum of {} links", port, max_links)
+        }
+    }
+}
+
+impl From<LinkError> for DataValue{
+    fn from(err: LinkError) -> DataValue{
+        use self::LinkError::*;
+        let mut map: BTreeMap<String, DataValue> = BTreeMap::new();
+        map.insert("message".into(), DataValue::from(format!("{}", err)));
+        match err{
+            UnknownPort(port) => {
+                map.insert("error".into(), DataValue::from("UnknownPort"));
+                map.insert("port".into(),  DataValue::from(port));
+            },
+            Direction{ source, target } => {
+                map.insert("error".into(),  DataValue::from("Direction"));
+                map.insert("source".into(), DataValue::from(source));
+                map.insert("target".into(), DataValue::from(target));
+            },
+            Incompatible{ source, target } => {
+                map.insert("error".into(),  DataValue::from("Incompatible"));
+                map.insert("source".into(), DataValue::from(source));
+                map.insert("target".into(), DataValue::from(target));
+            },
+            Full{ port, max_links } => {
+                map.insert("error".into(),     DataValue::from("Full"));
+                map.insert("port".into(),      DataValue::from(port));
+                map.insert("max_links".into(), DataValue::from(max_links as i32));
+            }
+        }
+        DataValue::Map(map)
+    }
+}
+
+pub static PROTOCOL_NAME:         &str = "selenologist-node-editor";
+pub static MSGPACK_PROTOCOL_NAME: &str = "selenologist-node-editor.msgpack";
+
+/// Wire format of a websocket connection.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Deserialize, Serialize)]
+pub enum Codec{
+    #[default]
+    Json,
+    MsgPack
+}
+
+impl Codec{
+    /// The websocket subprotocol that selects this codec for a whole connection.
+    pub fn protocol_name(&self) -> &'static str{
+        match *self{
+            Codec::Json    => PROTOCOL_NAME,
+            Codec::MsgPack => MSGPACK_PROTOCOL_NAME
+        }
+    }
+
+    #[allow(clippy::result_large_err)]
+    pub fn encode<T: Serialize>(&self, val: &T) -> ::std::result::Result<WsMessage, WsError>{
+        match *self{
+            Codec::Json =>
+                serde_json::to_string(val)
+                    .map(WsMessage::Text)
+                    .map_err(|e| WsError::new(WsErrorKind::Internal,
+                                              format!("JSON encoding failed {:?}", e))),
+            Codec::MsgPack => // the tagged enums need field names, so structs must be maps
+                rmp_serde::to_vec_named(val)
+                    .map(WsMessage::Binary)
+                    .map_err(|e| WsError::new(WsErrorKind::Internal,
+                                              format!("MessagePack encoding failed {:?}", e)))
+        }
+    }
 }
 
 #[derive(Copy, Clone, Debug)]
@@ -86,9 +321,6 @@ impl PartialEq for ClientType{
                 }
         }
     }
-    fn ne(&self, other: &Self) -> bool{
-        !self.eq(other)
-    }
 }
 
 impl ClientType{
@@ -109,39 +341,735 @@ pub struct GraphData{
     pub data:  BTreeMap<DataId, DataValue>
 }
 
+impl GraphData{
+    pub fn node(&self, path: &[NodeId]) -> Option<&Node>{
+        let (first, rest) = path.split_first()?;
+        let mut node = self.nodes.get(first)?;
+        for id in rest{
+            node = node.child(id)?;
+        }
+        Some(node)
+    }
+
+    pub fn node_mut(&mut self, path: &[NodeId]) -> Option<&mut Node>{
+        let (first, rest) = path.split_first()?;
+        let mut node = self.nodes.get_mut(first)?;
+        for id in rest{
+            node = node.child_mut(id)?;
+        }
+        Some(node)
+    }
+
+    /// The PortId of the node at `path`, which its ports are named after. That's the last
+    /// NodeId in the path, qualified with the Container's PortId for children of a Container.
+    pub fn port_id(&self, path: &[NodeId]) -> Option<PortId>{
+        let (first, rest) = path.split_first()?;
+        let mut node = self.nodes.get(first)?;
+        let mut id   = first.clone();
+        for child in rest{
+            id = match *node{
+                Node::Container{ .. } => container_child_id(&id, child.parse().ok()?),
+                _                     => child.clone()
+            };
+            node = node.child(child)?;
+        }
+        Some(id)
+    }
+
+    // whether removing the node at `path` renumbers any ports, which happens to the later
+    // children of a Container
+    fn shifts_ports(&self, path: &[NodeId]) -> bool{
+        self.container_ports_from(path, 1)
+    }
+
+    // whether inserting a node at `path` renumbers any ports, those of the Container child that's
+    // there now included
+    fn insert_shifts_ports(&self, path: &[NodeId]) -> bool{
+        self.container_ports_from(path, 0)
+    }
+
+    fn container_ports_from(&self, path: &[NodeId], offset: usize) -> bool{
+        let (id, parent) = match path.split_last(){
+            Some(s) => s,
+            None    => return false
+        };
+        match (self.node(parent), id.parse::<usize>()){
+            (Some(Node::Container{ nodes }), Ok(i)) =>
+                nodes.iter().skip(i + offset).any(Node::has_ports),
+            _ => false
+        }
+    }
+
+    /// Inserts `node` at `path`. Inserting into a Container ahead of children with ports is
+    /// refused, since it would change their PortIds.
+    pub fn insert_node(&mut self, path: &[NodeId], node: Node) -> Result<()>{
+        let (id, parent) = match path.split_last(){
+            Some(s) => s,
+            None    => return Err(String::from("Empty NodePath").into())
+        };
+        if parent.is_empty(){
+            if self.nodes.contains_key(id){
+                return Err(format!("Node {} already exists", id).into())
+            }
+            self.nodes.insert(id.clone(), node);
+            Ok(())
+        }
+        else if self.insert_shifts_ports(path){
+            Err(format!("Inserting at {:?} would change the ids of the ports after it", path).into())
+        }
+        else{
+            match self.node_mut(parent){
+                Some(p) => p.insert_child(id, node),
+                None    => Err(format!("Parent {:?} does not exist", parent).into())
+            }
+        }
+    }
+
+    /// Removes the node at `path`. Removing a Container child ahead of children with ports is
+    /// refused, since it would change their PortIds.
+    pub fn remove_node(&mut self, path: &[NodeId]) -> Result<Node>{
+        if self.shifts_ports(path){
+            return Err(format!("Removing {:?} would change the ids of the ports after it", path).into())
+        }
+        let removed = match path.split_last(){
+            Some((id, [])) =>
+                self.nodes.remove(id),
+            Some((id, parent)) =>
+                self.node_mut(parent).and_then(|p| p.remove_child(id)),
+            None => None
+        };
+        match removed{
+            Some(node) => Ok(node),
+            None       => Err(format!("Node {:?} does not exist", path).into())
+        }
+    }
+
+    /// Every port in the tree keyed by PortId.
+    pub fn port_map(&self) -> BTreeMap<PortId, &Node>{
+        let mut ports = BTreeMap::new();
+        for (id, node) in self.nodes.iter(){
+            node.collect_ports(id, &mut ports);
+        }
+        ports
+    }
+
+    fn check_link_in(ports: &BTreeMap<PortId, &Node>, source: &PortId, target: &PortId)
+        -> ::std::result::Result<(), LinkError>
+    {
+        let source_node = ports.get(source)
+            .ok_or_else(|| LinkError::UnknownPort(source.clone()))?;
+        let target_node = ports.get(target)
+            .ok_or_else(|| LinkError::UnknownPort(target.clone()))?;
+        if !can_link(source_node, target_node){
+            return Err(LinkError::Direction{ source: source.clone(), target: target.clone() })
+        }
+        // can_link only passes ports, so both specs are present
+        let (source_spec, target_spec) =
+            (source_node.port_spec().unwrap(), target_node.port_spec().unwrap());
+        if !source_spec.compatible(target_spec){
+            return Err(LinkError::Incompatible{ source: source.clone(), target: target.clone() })
+        }
+        Ok(())
+    }
+
+    pub fn has_link(&self, source: &PortId, target: &PortId) -> bool{
+        self.links
+            .get(source)
+            .map(|targets| targets.contains(target))
+            .unwrap_or(false)
+    }
+
+    /// Number of links to and from `port`.
+    pub fn link_count(&self, port: &PortId) -> usize{
+        let outgoing = self.links.get(port).map(|targets| targets.len()).unwrap_or(0);
+        let incoming = self.links.values().filter(|targets| targets.contains(port)).count();
+        outgoing + incoming
+    }
+
+    /// Checks that `port` has room for another link.
+    fn check_capacity(&self, ports: &BTreeMap<PortId, &Node>, port: &PortId)
+        -> ::std::result::Result<(), LinkError>
+    {
+        let max_links = ports.get(port)
+            .and_then(|node| node.port_spec())
+            .and_then(|spec| spec.max_links);
+        match max_links{
+            Some(max_links) if self.link_count(port) >= max_links =>
+                Err(LinkError::Full{ port: port.clone(), max_links }),
+            _ => Ok(())
+        }
+    }
+
+    fn check_new_link_in(&self, ports: &BTreeMap<PortId, &Node>,
+                         source: &PortId, target: &PortId)
+        -> ::std::result::Result<(), LinkError>
+    {
+        Self::check_link_in(ports, source, target)?;
+        if !self.has_link(source, target){ // an existing link doesn't need any more room
+            self.check_capacity(ports, source)?;
+            self.check_capacity(ports, target)?;
+        }
+        Ok(())
+    }
+
+    /// Checks that `source` and `target` name ports, that data can flow between them and that
+    /// both have room for the link.
+    pub fn check_link(&self, source: &PortId, target: &PortId)
+        -> ::std::result::Result<(), LinkError>
+    {
+        self.check_new_link_in(&self.port_map(), source, target)
+    }
+
+    /// Checks every link in the graph, returning all of the invalid ones.
+    pub fn check_links(&self) -> Vec<LinkError>{
+        let ports = self.port_map();
+        let bad_links = self.links
+            .iter()
+            .flat_map(|(source, targets)|
+                targets.iter().map(move |target| (source, target)))
+            .filter_map(|(source, target)|
+                Self::check_link_in(&ports, source, target).err());
+        let full_ports = ports
+            .iter()
+            .filter_map(|(port, node)|
+                match node.port_spec().and_then(|spec| spec.max_links){
+                    Some(max_links) if self.link_count(port) > max_links =>
+                        Some(LinkError::Full{ port: port.clone(), max_links }),
+                    _ => None
+                });
+        bad_links.chain(full_ports).collect()
+    }
+
+    /// Every port that `source` could currently be linked to.
+    pub fn link_targets(&self, source: &PortId) -> Vec<PortId>{
+        let ports = self.port_map();
+        ports.keys()
+             .filter(|target| !self.has_link(source, target) &&
+                              self.check_new_link_in(&ports, source, target).is_ok())
+             .cloned()
+             .collect()
+    }
+
+    /// Every link to or from any of `ports`.
+    pub fn links_touching(&self, ports: &BTreeSet<PortId>) -> Vec<(PortId, PortId)>{
+        self.links
+            .iter()
+            .flat_map(|(source, targets)|
+                targets.iter().map(move |target| (source, target)))
+            .filter(|&(source, target)| ports.contains(source) || ports.contains(target))
+            .map(|(source, target)| (source.clone(), target.clone()))
+            .collect()
+    }
+
+    /// Removes every link to or from any of `ports`, pruning sources left without targets.
+    pub fn unlink_ports(&mut self, ports: &BTreeSet<PortId>){
+        if ports.is_empty(){
+            return;
+        }
+        let links = ::std::mem::take(&mut self.links);
+        self.links = links
+            .into_iter()
+            .filter(|(source, _)| !ports.contains(source))
+            .map(|(source, targets)|{
+                let targets: BTreeSet<PortId> = targets
+                    .into_iter()
+                    .filter(|t| !ports.contains(t))
+                    .collect();
+                (source, targets)
+            })
+            .filter(|(_, targets)| !targets.is_empty())
+            .collect();
+    }
+
+    /// The top-level node that each port belongs to.
+    pub fn port_owners(&self) -> BTreeMap<PortId, NodeId>{
+        let mut owners = BTreeMap::new();
+        for (id, node) in self.nodes.iter(){
+            let mut ports = BTreeSet::new();
+            node.ports(id, &mut ports);
+            owners.extend(ports.into_iter().map(|port| (port, id.clone())));
+        }
+        owners
+    }
+
+    /// For every top-level node, the nodes that its ports link to.
+    pub fn node_adjacency(&self) -> BTreeMap<NodeId, BTreeSet<NodeId>>{
+        let owners = self.port_owners();
+        let mut adjacency: BTreeMap<NodeId, BTreeSet<NodeId>> =
+            self.nodes.keys().map(|id| (id.clone(), BTreeSet::new())).collect();
+        for (source, targets) in self.links.iter(){
+            if let Some(from) = owners.get(source){
+                let to = targets.iter().filter_map(|target| owners.get(target)).cloned();
+                adjacency.get_mut(from).unwrap().extend(to);
+            }
+        }
+        adjacency
+    }
+
+    /// A path of nodes that leads back to where it started, e.g. `[a, b, c, a]`, if the links
+    /// between nodes form a cycle.
+    pub fn find_cycle(&self) -> Option<Vec<NodeId>>{
+        let adjacency = self.node_adjacency();
+        let mut done  = BTreeSet::new();
+        adjacency.keys().filter_map(|id| find_cycle_from(&adjacency, id, &mut done)).next()
+    }
+
+    /// Orders the top-level nodes so that each comes after every node linking into it, along
+    /// with the nodes that can't be ordered because they're in or downstream of a cycle.
+    pub fn partial_order(&self) -> (Vec<NodeId>, Vec<NodeId>){
+        let adjacency = self.node_adjacency();
+        let mut in_degree: BTreeMap<&NodeId, usize> =
+            adjacency.keys().map(|id| (id, 0)).collect();
+        for targets in adjacency.values(){
+            for target in targets{
+                *in_degree.get_mut(target).unwrap() += 1;
+            }
+        }
+
+        let mut ready: VecDeque<&NodeId> = in_degree
+            .iter()
+            .filter(|&(_, &degree)| degree == 0)
+            .map(|(&id, _)| id)
+            .collect();
+        let mut order = Vec::new();
+        while let Some(id) = ready.pop_front(){
+            order.push(id.clone());
+            for target in adjacency[id].iter(){
+                let degree = in_degree.get_mut(target).unwrap();
+                *degree -= 1;
+                if *degree == 0{
+                    ready.push_back(target);
+                }
+            }
+        }
+
+        let unordered = in_degree
+            .into_iter()
+            .filter(|&(_, degree)| degree > 0)
+            .map(|(id, _)| id.clone())
+            .collect();
+        (order, unordered)
+    }
+
+    /// Every top-level node ordered so that each comes after every node linking into it, or the
+    /// path of a cycle that makes that impossible.
+    pub fn topological_order(&self) -> ::std::result::Result<Vec<NodeId>, Vec<NodeId>>{
+        let (order, unordered) = self.partial_order();
+        if unordered.is_empty(){
+            Ok(order)
+        }
+        else{
+            Err(self.find_cycle().unwrap_or(unordered))
+        }
+    }
+
+    /// Every node that `id` feeds into, directly or through other nodes. Only includes `id`
+    /// itself if it's part of a cycle.
+    pub fn downstream(&self, id: &NodeId) -> BTreeSet<NodeId>{
+        reachable(&self.node_adjacency(), id)
+    }
+
+    /// Every node that feeds into `id`, directly or through other nodes. Only includes `id`
+    /// itself if it's part of a cycle.
+    pub fn upstream(&self, id: &NodeId) -> BTreeSet<NodeId>{
+        let mut reversed: BTreeMap<NodeId, BTreeSet<NodeId>> = BTreeMap::new();
+        for (source, targets) in self.node_adjacency(){
+            for target in targets{
+                reversed.entry(target).or_default().insert(source.clone());
+            }
+        }
+        reachable(&reversed, id)
+    }
+}
+
+// depth first search from `start` that returns the first path to revisit a node still on the
+// path, `done` holds nodes whose descendants are already known not to cycle. Keeps its own stack
+// of unvisited targets so long chains of nodes can't overflow the thread's stack.
+fn find_cycle_from<'a>(adjacency: &'a BTreeMap<NodeId, BTreeSet<NodeId>>, start: &'a NodeId,
+                       done: &mut BTreeSet<&'a NodeId>)
+    -> Option<Vec<NodeId>>
+{
+    if done.contains(start){
+        return None;
+    }
+    let mut path: Vec<&'a NodeId> = vec![start];
+    let mut on_path: BTreeSet<&'a NodeId> = path.iter().cloned().collect();
+    let mut stack = vec![adjacency.get(start).map(|targets| targets.iter())];
+    while let Some(targets) = stack.last_mut(){
+        match targets.as_mut().and_then(|targets| targets.next()){
+            Some(id) => {
+                if on_path.contains(id){
+                    let from = path.iter().position(|&n| n == id).unwrap();
+                    let mut cycle: Vec<NodeId> = path[from..].iter().map(|&n| n.clone()).collect();
+                    cycle.push(id.clone());
+                    return Some(cycle);
+                }
+                if !done.contains(id){
+                    on_path.insert(id);
+                    path.push(id);
+                    stack.push(adjacency.get(id).map(|targets| targets.iter()));
+                }
+            },
+            None => {
+                let id = path.pop().unwrap();
+                on_path.remove(id);
+                done.insert(id);
+                stack.pop();
+            }
+        }
+    }
+    None
+}
+
+fn reachable(adjacency: &BTreeMap<NodeId, BTreeSet<NodeId>>, id: &NodeId) -> BTreeSet<NodeId>{
+    let mut found = BTreeSet::new();
+    let mut stack = vec![id];
+    while let Some(next) = stack.pop(){
+        if let Some(targets) = adjacency.get(next){
+            for target in targets{
+                if found.insert(target.clone()){
+                    stack.push(target);
+                }
+            }
+        }
+    }
+    found
+}
+
+fn to_response<T>(result: Result<T>) -> Response{
+    match result{
+        Ok(_)  => Response::Ok,
+        Err(e) => Response::Error(DataValue::from(format!("{}", e)))
+    }
+}
+
+static HISTORY_LIMIT: usize = 100;
+
+/// An applied edit along with the commands that revert it.
+#[derive(Clone, Debug)]
+struct Edit{
+    forward: Vec<Command>,
+    inverse: Vec<Command>
+}
+
+#[derive(Default)]
+pub struct History{
+    undo: VecDeque<Edit>,
+    redo: Vec<Edit>
+}
+
+impl History{
+    fn push_undo(&mut self, edit: Edit){
+        if self.undo.len() >= HISTORY_LIMIT{
+            self.undo.pop_front();
+        }
+        self.undo.push_back(edit);
+    }
+}
+
+static UPDATE_LOG_LIMIT: usize = 1000;
+
+/// The most recent updates broadcast for a graph, numbered in the order they were sent so that a
+/// client that reconnects can catch up on only the ones it missed.
+pub struct UpdateLog{
+    next:    Seq,
+    entries: VecDeque<(Seq, ClientType, Update)>
+}
+
+impl Default for UpdateLog{
+    fn default() -> Self{
+        UpdateLog::new(0)
+    }
+}
+
+impl UpdateLog{
+    /// The epoch goes in the upper 32 bits of every sequence number, so that one from before a
+    /// restart isn't mistaken for one handed out after it as long as each run has its own epoch.
+    pub fn new(epoch: u32) -> UpdateLog{
+        UpdateLog{
+            next:    ((epoch as Seq) << 32) + 1,
+            entries: VecDeque::new()
+        }
+    }
+
+    fn push(&mut self, client_type: ClientType, update: Update) -> Seq{
+        let seq = self.next;
+        self.next += 1;
+        if self.entries.len() >= UPDATE_LOG_LIMIT{
+            self.entries.pop_front();
+        }
+        self.entries.push_back((seq, client_type, update));
+        seq
+    }
+
+    /// Sequence number of the most recent update, which is the state a full SetGraph represents.
+    pub fn last(&self) -> Seq{
+        self.next - 1
+    }
+
+    /// Updates for `client_type` sent after `last`, or None if some of them are no longer logged.
+    pub fn since(&self, client_type: &ClientType, last: Seq) -> Option<Vec<Sequenced>>{
+        let first = self.entries.front().map(|&(seq, _, _)| seq).unwrap_or(self.next);
+        if last > self.last() || last + 1 < first{
+            return None;
+        }
+        Some(self.entries
+                 .iter()
+                 .filter(|&&(seq, ref c, _)| seq > last && c == client_type)
+                 .map(|&(seq, _, ref update)| Sequenced{ seq, update: update.clone() })
+                 .collect())
+    }
+}
+
+/// Connections attached to a graph, keyed by their ws token.
+pub type Listeners = BTreeMap<usize, (ClientType, Codec, WsSender)>;
+
 #[derive(Clone, Default)]
 pub struct Graph{
-    pub data:      Rc<RefCell<GraphData>>,
-    pub listeners: Rc<RefCell<BTreeMap<usize, (ClientType, WsSender)>>>
+    pub data:      Arc<RwLock<GraphData>>,
+    pub listeners: Arc<Mutex<Listeners>>,
+    pub history:   Arc<Mutex<History>>,
+    pub log:       Arc<Mutex<UpdateLog>>
 }
 
 impl Graph{
-    pub fn repeat_to(&self, client_type: ClientType, msg: WsMessage){
-        trace!("there are {} listeners", self.listeners.borrow().len());
-        self.listeners
-            .borrow()
-            .values()
-            .for_each(|&(ref c, ref s): &(ClientType, WsSender)|{
-                if c == &client_type {
-                    trace!("sent msg to {:?}", client_type);
-                    match s.send(msg.clone()){
-                        Ok(_)  => {},
-                        Err(e) => {debug!("failed to repeat message to listener {:?}", e);}
+    /// A graph holding `data` whose updates are numbered within `epoch`.
+    pub fn with_data(data: GraphData, epoch: u32) -> Graph{
+        Graph{
+            data:      Arc::new(RwLock::new(data)),
+            listeners: Arc::default(),
+            history:   Arc::default(),
+            log:       Arc::new(Mutex::new(UpdateLog::new(epoch)))
+        }
+    }
+
+    /// Applies an editing command, recording it so that it can be undone, and broadcasts it to
+    /// every listener if it succeeds.
+    pub fn edit(&self, command: &Command) -> Response{
+        // held throughout so that edits from other threads can't slip in between inverse and apply
+        let mut history = self.history.lock().unwrap();
+        // and the log too, so that a client attaching meanwhile gets either the graph from before
+        // the edit followed by the edit, or the graph after it and not the edit again
+        let mut log  = self.log.lock().unwrap();
+        let inverse  = self.inverse(command);
+        let response = self.apply(command);
+        if response == Response::Ok{
+            history.push_undo(Edit{ forward: vec![command.clone()], inverse });
+            history.redo.clear();
+            self.repeat_logged(&mut log, ClientType::Both, command.clone());
+        }
+        response
+    }
+
+    /// Applies a command without recording it for undo, broadcasting it to the listeners of
+    /// `client_type` if it succeeds. Backends change graphs this way so that undoing in a
+    /// frontend only ever reverts the frontend's own edits.
+    pub fn apply_unrecorded(&self, command: &Command, client_type: ClientType) -> Response{
+        // held so that the change can't land between an edit's inverse and apply
+        let mut history = self.history.lock().unwrap();
+        let mut log  = self.log.lock().unwrap();
+        let response = self.apply(command);
+        if let Command::SetGraph{ .. } = *command{
+            // the recorded edits were made to a graph that's gone now
+            history.undo.clear();
+            history.redo.clear();
+        }
+        if response == Response::Ok{
+            self.repeat_logged(&mut log, client_type, command.clone());
+        }
+        response
+    }
+
+    /// Reverts the most recent edit and broadcasts the commands that did so. Either all of the
+    /// edit is reverted or, if one of its commands fails, none of it is and the history is left
+    /// as it was.
+    pub fn undo(&self) -> Response{
+        let mut history = self.history.lock().unwrap();
+        let mut log = self.log.lock().unwrap();
+        let edit = match history.undo.back(){
+            Some(edit) => edit.clone(),
+            None       => return Response::Warning(DataValue::from("Nothing to undo"))
+        };
+        let response = self.replay(&mut log, &edit.inverse);
+        if response == Response::Ok{
+            history.undo.pop_back();
+            history.redo.push(edit);
+        }
+        response
+    }
+
+    /// Re-applies the most recently undone edit, all or nothing like `undo`.
+    pub fn redo(&self) -> Response{
+        let mut history = self.history.lock().unwrap();
+        let mut log = self.log.lock().unwrap();
+        let edit = match history.redo.last(){
+            Some(edit) => edit.clone(),
+            None       => return Response::Warning(DataValue::from("Nothing to redo"))
+        };
+        let response = self.replay(&mut log, &edit.forward);
+        if response == Response::Ok{
+            history.redo.pop();
+            history.push_undo(edit);
+        }
+        response
+    }
+
+    // applies commands from the history, rolling back the ones that went through if any of them
+    // fails so that only a whole edit is ever broadcast
+    fn replay(&self, log: &mut UpdateLog, commands: &[Command]) -> Response{
+        let mut rollback = Vec::with_capacity(commands.len());
+        for command in commands{
+            let inverse  = self.inverse(command);
+            let response = self.apply(command);
+            if response != Response::Ok{
+                // the graph has changed in ways the history doesn't know about, e.g. by a backend
+                for command in rollback.iter().rev().flatten(){
+                    let undone = self.apply(command);
+                    if undone != Response::Ok{
+                        error!("Rolling back {:?} failed: {:?}", command, undone);
                     }
                 }
-            });
+                return Response::Error(DataValue::from(format!(
+                    "Can't replay {:?} from history: {:?}", command, response)));
+            }
+            rollback.push(inverse);
+        }
+        for command in commands{
+            self.repeat_logged(log, ClientType::Both, command.clone());
+        }
+        Response::Ok
+    }
+
+    /// Applies an editing command without touching the history.
+    fn apply(&self, command: &Command) -> Response{
+        use self::Command::*;
+        match *command{
+            AddLink{ ref source, ref target } => self.add_link(source, target),
+            DelLink{ ref source, ref target } => self.del_link(source, target),
+            AddNode{ ref path, ref node }     => self.add_node(path, node),
+            RemoveNode{ ref path }            => self.remove_node(path),
+            MoveNode{ ref path, ref to }      => self.move_node(path, to),
+            ReplaceNode{ ref path, ref node } => self.replace_node(path, node),
+            SetData{ ref id, ref value }      => {
+                self.set_data(id, value);
+                Response::Ok
+            },
+            SetGraph{ ref graph }             => {
+                *self.data.write().unwrap() = (**graph).clone();
+                Response::Ok
+            },
+            _ => Response::Error(DataValue::from(format!("{:?} is not an edit", command)))
+        }
+    }
+
+    /// Commands that revert `command` if it's applied to the graph as it is now.
+    fn inverse(&self, command: &Command) -> Vec<Command>{
+        use self::Command::*;
+        let data = self.data.read().unwrap();
+        let relink = |links: Vec<(PortId, PortId)>|
+            links.into_iter().map(|(source, target)| AddLink{ source, target });
+        match *command{
+            AddLink{ ref source, ref target } =>
+                vec![DelLink{ source: source.clone(), target: target.clone() }],
+            DelLink{ ref source, ref target } =>
+                vec![AddLink{ source: source.clone(), target: target.clone() }],
+            AddNode{ ref path, .. } =>
+                vec![RemoveNode{ path: path.clone() }],
+            RemoveNode{ ref path } =>
+                match (data.node(path), data.port_id(path)){
+                    (Some(old), Some(id)) => {
+                        let mut ports = BTreeSet::new();
+                        old.ports(&id, &mut ports);
+                        let mut inverse = vec![AddNode{ path: path.clone(), node: old.clone() }];
+                        inverse.extend(relink(data.links_touching(&ports)));
+                        inverse
+                    },
+                    _ => vec![]
+                },
+            MoveNode{ ref path, ref to } =>
+                vec![MoveNode{ path: to.clone(), to: path.clone() }],
+            ReplaceNode{ ref path, ref node } =>
+                match (data.node(path), data.port_id(path)){
+                    (Some(old), Some(id)) => {
+                        let mut old_ports = BTreeSet::new();
+                        let mut new_ports = BTreeSet::new();
+                        old.ports(&id, &mut old_ports);
+                        node.ports(&id, &mut new_ports);
+                        let dropped = old_ports.difference(&new_ports).cloned().collect();
+                        let mut inverse = vec![ReplaceNode{ path: path.clone(), node: old.clone() }];
+                        inverse.extend(relink(data.links_touching(&dropped)));
+                        inverse
+                    },
+                    _ => vec![]
+                },
+            // there's no command to unset data so an id that didn't exist goes back to Nil
+            SetData{ ref id, .. } =>
+                vec![SetData{
+                    id:    id.clone(),
+                    value: data.data.get(id).cloned().unwrap_or(DataValue::Nil)
+                }],
+            SetGraph{ .. } =>
+                vec![SetGraph{ graph: Arc::new(data.clone()) }],
+            _ => vec![]
+        }
+    }
+
+    /// Broadcasts `update` to the listeners of `client_type`, numbering and logging it first.
+    pub fn repeat_to<T: Into<Update>>(&self, client_type: ClientType, update: T){
+        // the log stays locked while sending so that every listener gets updates in seq order
+        let mut log = self.log.lock().unwrap();
+        self.repeat_logged(&mut log, client_type, update);
+    }
+
+    // as repeat_to, for callers that locked the log before changing the graph
+    fn repeat_logged<T: Into<Update>>(&self, log: &mut UpdateLog, client_type: ClientType, update: T){
+        let update    = update.into();
+        let seq       = log.push(client_type, update.clone());
+        let update    = Sequenced{ seq, update };
+        let listeners = self.listeners.lock().unwrap();
+        trace!("there are {} listeners", listeners.len());
+        // listeners can use different codecs, encode at most once for each
+        let mut encoded: Vec<(Codec, WsMessage)> = Vec::new();
+        for &(ref c, codec, ref s) in listeners.values(){
+            if c != &client_type {
+                continue;
+            }
+            let cached = encoded.iter().position(|&(e, _)| e == codec);
+            let msg = match cached{
+                Some(i) => encoded[i].1.clone(),
+                None    => match codec.encode(&update){
+                    Ok(msg) => {
+                        encoded.push((codec, msg.clone()));
+                        msg
+                    },
+                    Err(e)  => {
+                        error!("failed to encode {:?} as {:?}: {:?}", update, codec, e);
+                        continue;
+                    }
+                }
+            };
+            trace!("sent msg to {:?}", client_type);
+            match s.send(msg){
+                Ok(_)  => {},
+                Err(e) => {debug!("failed to repeat message to listener {:?}", e);}
+            }
+        }
     }
     pub fn remove_listener(&self, token: usize){
-        self.listeners.borrow_mut().remove(&token);
+        self.listeners.lock().unwrap().remove(&token);
     }
 
     pub fn add_link(&self, source_port: &PortId, target_port: &PortId)
         -> Response
     {
-        let mut data = self.data.borrow_mut();
+        let mut data = self.data.write().unwrap();
+
+        if let Err(e) = data.check_link(source_port, target_port){
+            return Response::Error(DataValue::from(e))
+        }
 
         let entry = data.links.entry(source_port.clone())
-            .or_insert(BTreeSet::new());
+            .or_default();
         let is_new = entry.insert(target_port.clone());
         if !is_new {
             Response::Warning(DataValue::from(format!(
@@ -152,20 +1080,193 @@ impl Graph{
             Response::Ok
         }
     }
+
+    pub fn add_node(&self, path: &NodePath, node: &Node) -> Response{
+        to_response(self.data.write().unwrap().insert_node(path, node.clone()))
+    }
+
+    /// Removes the node at `path` along with every link to its ports.
+    pub fn remove_node(&self, path: &NodePath) -> Response{
+        let mut data = self.data.write().unwrap();
+        let id = data.port_id(path);
+        let result = data.remove_node(path)
+            .map(|node|{
+                let mut ports = BTreeSet::new();
+                // remove_node only succeeds if there's a node at `path`, which has a PortId
+                node.ports(id.as_ref().unwrap(), &mut ports);
+                ports
+            });
+        if let Ok(ref ports) = result{
+            data.unlink_ports(ports);
+        }
+        to_response(result)
+    }
+
+    /// Re-parents the node at `path` so that it ends up at `to`. Links are kept, so moves that
+    /// would change any PortId are refused: renaming a node with ports, or moving one into or
+    /// out of a Container ahead of children with ports.
+    pub fn move_node(&self, path: &NodePath, to: &NodePath) -> Response{
+        let mut data = self.data.write().unwrap();
+        // the move happens on a copy of the nodes so that a failed one leaves the graph as it was
+        let mut moved = GraphData{ nodes: data.nodes.clone(), ..GraphData::default() };
+        let old_id = match data.port_id(path){
+            Some(id) => id,
+            None     => return Response::Error(DataValue::from(format!(
+                            "Node {:?} does not exist", path)))
+        };
+        let node = match moved.remove_node(path){
+            Ok(node) => node,
+            Err(e)   => return to_response::<()>(Err(e))
+        };
+        let mut old_ports = BTreeSet::new();
+        node.ports(&old_id, &mut old_ports);
+        if let Err(e) = moved.insert_node(to, node){
+            return to_response::<()>(Err(e))
+        }
+        let new_ports = match (moved.node(to), moved.port_id(to)){
+            (Some(node), Some(new_id)) => {
+                let mut new_ports = BTreeSet::new();
+                node.ports(&new_id, &mut new_ports);
+                new_ports
+            },
+            _ => BTreeSet::new()
+        };
+        if old_ports != new_ports{
+            return Response::Error(DataValue::from(format!(
+                "Can't move {:?} to {:?}, the move would change the ids of ports",
+                path, to)))
+        }
+        data.nodes = moved.nodes;
+        Response::Ok
+    }
+
+    /// Replaces the node at `path`, dropping links to any ports the new node doesn't have.
+    pub fn replace_node(&self, path: &NodePath, node: &Node) -> Response{
+        let mut data = self.data.write().unwrap();
+        let id = data.port_id(path);
+        let old_ports = match (id, data.node_mut(path)){
+            (Some(id), Some(old)) => {
+                let mut old_ports = BTreeSet::new();
+                let mut new_ports = BTreeSet::new();
+                old.ports(&id, &mut old_ports);
+                node.ports(&id, &mut new_ports);
+                *old = node.clone();
+                old_ports.difference(&new_ports).cloned().collect::<BTreeSet<PortId>>()
+            },
+            _ => return Response::Error(DataValue::from(format!(
+                        "Node {:?} does not exist", path)))
+        };
+        data.unlink_ports(&old_ports);
+        Response::Ok
+    }
+
+    pub fn link_targets(&self, source: &PortId) -> Response{
+        Response::LinkTargets{
+            source:  source.clone(),
+            targets: self.data.read().unwrap().link_targets(source)
+        }
+    }
+
+    pub fn find_cycle(&self) -> Response{
+        Response::Cycle{
+            nodes: self.data.read().unwrap().find_cycle().unwrap_or_default()
+        }
+    }
+
+    /// Stores `value` under `id`, returning true if `id` wasn't already set.
+    pub fn set_data(&self, id: &DataId, value: &DataValue) -> bool{
+        self.data
+            .write()
+            .unwrap()
+            .data
+            .insert(id.clone(), value.clone())
+            .is_none()
+    }
+
+    pub fn del_link(&self, source_port: &PortId, target_port: &PortId)
+        -> Response
+    {
+        let mut data = self.data.write().unwrap();
+
+        let (existed, now_empty) = match data.links.get_mut(source_port){
+            Some(targets) => (targets.remove(target_port), targets.is_empty()),
+            None          => (false, false)
+        };
+        if now_empty{ // don't leave sources with no targets lying around
+            data.links.remove(source_port);
+        }
+        if !existed {
+            Response::Warning(DataValue::from(format!(
+                "Link {} -> {} does not exist",
+                source_port, target_port)))
+        }
+        else{
+            Response::Ok
+        }
+    }
 }
 
 #[derive(Clone, Default)]
-pub struct GraphStore(pub Rc<RefCell<BTreeMap<GraphId, Graph>>>,
-                      Rc<Cell<GraphId>>);
+pub struct GraphStore{
+    pub graphs: Arc<RwLock<BTreeMap<GraphId, Graph>>>,
+    next_id:    Arc<Mutex<GraphId>>,
+    persister:  Option<Persister>,
+    evaluator:  Option<Arc<Evaluator>>,
+    // numbers the updates of every graph, see UpdateLog::new
+    epoch:      u32
+}
 
 impl GraphStore{
+    /// Builds a store from previously saved graphs that saves every change with `persister`.
+    /// `epoch` must differ from that of every earlier run, see `persist::next_epoch`.
+    pub fn restore(persister: Persister, graphs: BTreeMap<GraphId, GraphData>, next_id: GraphId,
+                   epoch: u32)
+        -> GraphStore
+    {
+        let graphs = graphs
+            .into_iter()
+            .map(|(id, data)| (id, Graph::with_data(data, epoch)))
+            .collect();
+        GraphStore{
+            graphs:    Arc::new(RwLock::new(graphs)),
+            next_id:   Arc::new(Mutex::new(next_id)),
+            persister: Some(persister),
+            evaluator: None,
+            epoch
+        }
+    }
+
+    /// Evaluates graphs with `evaluator` after they're edited.
+    pub fn set_evaluator(&mut self, evaluator: Evaluator){
+        self.evaluator = Some(Arc::new(evaluator));
+    }
+
     fn new_id(&self) -> GraphId{
-        let new_id = self.1.get();
-        self.1.set(new_id + 1);
+        let mut next_id = self.next_id.lock().unwrap();
+        let new_id = *next_id;
+        self.set_next_id(&mut next_id, new_id + 1);
         new_id
     }
+    // takes the locked next id so that concurrent calls can't hand out the same id twice
+    fn set_next_id(&self, locked: &mut GraphId, next_id: GraphId){
+        *locked = next_id;
+        if let Some(ref persister) = self.persister{
+            persister.save_next_id(next_id);
+        }
+    }
+
+    /// Must be called after modifying a graph so that the change gets persisted. The graph is
+    /// only marked as changed, it's serialized later on the persistence thread.
+    pub fn changed(&self, id: GraphId){
+        if let Some(ref persister) = self.persister{
+            if let Ok(g) = self.get(id){
+                persister.save(id, g.data.clone());
+            }
+        }
+    }
+
     pub fn get(&self, id: GraphId) -> Result<Graph>{
-        if let Some(g) = self.0.borrow().get(&id){
+        if let Some(g) = self.graphs.read().unwrap().get(&id){
             Ok(g.clone())
         }
         else{
@@ -173,47 +1274,119 @@ impl GraphStore{
         }
     }
     pub fn contains_key(&self, id: GraphId) -> bool{
-        self.0.borrow().contains_key(&id)
+        self.graphs.read().unwrap().contains_key(&id)
     }
     pub fn insert(&self, id: GraphId, graph: Graph){
-        self.0.borrow_mut().insert(id, graph);
+        self.graphs.write().unwrap().insert(id, graph);
     }
     pub fn remove_listener(&self, graph: GraphId, token: usize) -> Result<()>{
-        Ok(self.get(graph)?.remove_listener(token))
+        self.get(graph)?.remove_listener(token);
+        Ok(())
     }
    
+    #[allow(clippy::new_ret_no_self)]
     pub fn new(&self, graph: Graph) -> GraphId{
         let id = self.new_id();
         self.insert(id, graph);
+        self.changed(id);
         id
     }
     pub fn new_empty(&self) -> GraphId{
-        self.new(Graph::default())
+        self.new(Graph::with_data(GraphData::default(), self.epoch))
     }
     pub fn empty_at(&self, id: GraphId) -> GraphId{
-        self.insert(id, Graph::default());
+        {
+            let mut next_id = self.next_id.lock().unwrap();
+            if id >= *next_id{ // keep new_id from handing this id out again
+                self.set_next_id(&mut next_id, id + 1);
+            }
+        }
+        self.insert(id, Graph::with_data(GraphData::default(), self.epoch));
+        self.changed(id);
         id
     }
 
-    pub fn attach(&self, id: GraphId, client_type: ClientType, sender: WsSender) -> Result<usize>{
+    pub fn attach(&self, id: GraphId, client_type: ClientType, codec: Codec, sender: WsSender)
+        -> Result<usize>
+    {
         let g = self.get(id)?;
-        let mut l = g.listeners.borrow_mut();
-        l.insert(sender.token().0, (client_type, sender));
+        let mut l = g.listeners.lock().unwrap();
+        l.insert(sender.token().0, (client_type, codec, sender));
         Ok(l.len())
     }
-    pub fn repeat_to(&self, id: GraphId, client_type: ClientType, msg: WsMessage) -> Result<()>{
+    pub fn repeat_to<T: Into<Update>>(&self, id: GraphId, client_type: ClientType, update: T) -> Result<()>{
         self.get(id)?
-            .repeat_to(client_type, msg);
+            .repeat_to(client_type, update);
         Ok(())
     }
-    pub fn set_graph(&self, id: GraphId, graph_data: Rc<RefCell<GraphData>>) -> Result<()>{
-        let g = self.get(id)?;
-        g.data.swap(&graph_data);
+    /// Applies an editing command from a frontend to graph `id`, recording it for undo,
+    /// broadcasting it and persisting it.
+    pub fn edit(&self, id: GraphId, command: &Command) -> Result<Response>{
+        let response = self.get(id)?.edit(command);
+        if response == Response::Ok{
+            self.changed(id);
+        }
+        Ok(response)
+    }
+    /// Applies a command that didn't come from a frontend to graph `id`, broadcasting it to
+    /// `client_type` and persisting it without recording it for undo.
+    pub fn apply(&self, id: GraphId, command: &Command, client_type: ClientType) -> Result<Response>{
+        let response = self.get(id)?.apply_unrecorded(command, client_type);
+        if response == Response::Ok{
+            self.changed(id);
+        }
+        Ok(response)
+    }
+    /// Runs the evaluator over graph `id`, applying and broadcasting every value it changes.
+    /// Must be called after an edit has been repeated so that clients see the edit first. If the
+    /// edit only set the value under `changed`, only the nodes using it are evaluated.
+    pub fn evaluate(&self, id: GraphId, changed: Option<&DataId>) -> Result<()>{
+        if let Some(ref evaluator) = self.evaluator{
+            let graph = self.get(id)?;
+            // results aren't edits, undoing the edit that caused them re-evaluates instead. The
+            // history is still locked before evaluating so that no edit can land between reading
+            // the graph and applying what was worked out from it
+            let _history = graph.history.lock().unwrap();
+            let mut log  = graph.log.lock().unwrap();
+            let changes  = evaluator.evaluate(&graph.data.read().unwrap(), changed);
+            if changes.is_empty(){
+                return Ok(());
+            }
+            for command in changes{
+                graph.apply(&command);
+                graph.repeat_logged(&mut log, ClientType::Both, command);
+            }
+            self.changed(id);
+        }
         Ok(())
     }
+    /// Replaces graph `id` with `graph_data`, broadcasting the new graph to `client_type`.
+    pub fn set_graph(&self, id: GraphId, graph_data: Arc<GraphData>, client_type: ClientType) -> Result<()>{
+        // the graph is copied rather than swapped so that graph_data keeps the new graph for repeating
+        self.apply(id, &Command::SetGraph{ graph: graph_data }, client_type)?;
+        Ok(())
+    }
+    /// Sets a value in graph `id` and broadcasts it to `client_type`, returning true if
+    /// `data_id` wasn't already set.
+    pub fn set_data(&self, id: GraphId, data_id: &DataId, value: &DataValue, client_type: ClientType)
+        -> Result<bool>
+    {
+        let graph  = self.get(id)?;
+        let is_new = {
+            // as apply_unrecorded, but checking whether the value is new in the same write
+            let _history = graph.history.lock().unwrap();
+            let mut log  = graph.log.lock().unwrap();
+            let is_new   = graph.set_data(data_id, value);
+            graph.repeat_logged(&mut log, client_type,
+                                Command::SetData{ id: data_id.clone(), value: value.clone() });
+            is_new
+        };
+        self.changed(id);
+        Ok(is_new)
+    }
 
     pub fn list(&self) -> GraphList{
-        let v = self.0.borrow().keys().cloned().collect();
+        let v = self.graphs.read().unwrap().keys().cloned().collect();
         GraphList{
             list: v
         }
@@ -222,7 +1395,7 @@ impl GraphStore{
 
 #[derive(Debug, PartialEq, Deserialize, Serialize)]
 pub struct GraphList{
-    list: Vec<GraphId>
+    pub list: Vec<GraphId>
 }
 
 #[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
@@ -230,17 +1403,110 @@ pub struct GraphList{
 pub enum Command{
     AddLink {source: PortId, target: PortId},
     DelLink {source: PortId, target: PortId},
+    LinkTargets {source: PortId},
+    FindCycle,
+    // undo and redo only ever revert and re-apply a frontend's own edits, so only frontends may
+    // send them
+    Undo,
+    Redo,
     SetData {id:     DataId, value:  DataValue},
-    SetGraph{graph: Rc<RefCell<GraphData>>},
-    FrontendAttach {id: GraphId},
-    BackendAttach  {id: Option<GraphId>},
+    SetGraph{graph: Arc<GraphData>},
+    AddNode     {path: NodePath, node: Node},
+    RemoveNode  {path: NodePath},
+    MoveNode    {path: NodePath, to: NodePath},
+    ReplaceNode {path: NodePath, node: Node},
+    // codec switches the connection's wire format from the attach onwards,
+    // acks asks for Ok responses to be sent as well,
+    // since is the last seq a reconnecting frontend saw, so only what it missed is sent
+    FrontendAttach {id: GraphId,         #[serde(default)] codec: Option<Codec>,
+                                         #[serde(default)] acks:  bool,
+                                         #[serde(default)] since: Option<Seq>},
+    BackendAttach  {id: Option<GraphId>, #[serde(default)] codec: Option<Codec>,
+                                         #[serde(default)] acks:  bool},
+}
+
+/// A Command as sent by a client, optionally with an id that's echoed back on its Response.
+/// On the wire the id is a `req` field in the Command itself.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Envelope{
+    pub req:     Option<RequestId>,
+    pub command: Command
+}
+
+impl Serialize for Envelope{
+    fn serialize<S: Serializer>(&self, serializer: S) -> ::std::result::Result<S::Ok, S::Error>{
+        wire::serialize_with(serializer, &self.command, "req", &self.req)
+    }
+}
+
+impl<'de> Deserialize<'de> for Envelope{
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> ::std::result::Result<Self, D::Error>{
+        let (command, req) = wire::deserialize_with(deserializer, "req")?;
+        Ok(Envelope{ req, command })
+    }
+}
+
+impl From<Command> for Envelope{
+    fn from(command: Command) -> Self{
+        Envelope{ req: None, command }
+    }
+}
+
+/// A Response along with the id of the request that caused it, if it had one, which is sent as
+/// a `req` field in the Response.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Reply{
+    pub req:      Option<RequestId>,
+    pub response: Response
+}
+
+impl Serialize for Reply{
+    fn serialize<S: Serializer>(&self, serializer: S) -> ::std::result::Result<S::Ok, S::Error>{
+        wire::serialize_with(serializer, &self.response, "req", &self.req)
+    }
+}
+
+impl<'de> Deserialize<'de> for Reply{
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> ::std::result::Result<Self, D::Error>{
+        let (response, req) = wire::deserialize_with(deserializer, "req")?;
+        Ok(Reply{ req, response })
+    }
+}
+
+impl From<Response> for Reply{
+    fn from(response: Response) -> Self{
+        Reply{ req: None, response }
+    }
+}
+
+/// A broadcast Update along with its position in the graph's UpdateLog, sent as a `seq` field
+/// next to the Update's variant.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Sequenced{
+    pub seq:    Seq,
+    pub update: Update
+}
+
+impl Serialize for Sequenced{
+    fn serialize<S: Serializer>(&self, serializer: S) -> ::std::result::Result<S::Ok, S::Error>{
+        wire::serialize_with(serializer, &self.update, "seq", &Some(self.seq))
+    }
+}
+
+impl<'de> Deserialize<'de> for Sequenced{
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> ::std::result::Result<Self, D::Error>{
+        match wire::deserialize_with(deserializer, "seq")?{
+            (update, Some(seq)) => Ok(Sequenced{ seq, update }),
+            (_, None)           => Err(de::Error::missing_field("seq"))
+        }
+    }
 }
 
 #[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
 // externally tagged (serde default)
 pub enum Update{
     Command(Command),
-    Response(Response)
+    Response(Reply)
 }
 
 impl From<Command> for Update{
@@ -251,6 +1517,12 @@ impl From<Command> for Update{
 
 impl From<Response> for Update{
     fn from(val: Response) -> Self{
+        Update::Response(val.into())
+    }
+}
+
+impl From<Reply> for Update{
+    fn from(val: Reply) -> Self{
         Update::Response(val)
     }
 }
@@ -263,7 +1535,9 @@ pub enum DataValue{
     Int(i32),
     Float(f32),
     String(String),
-    Graph(Rc<RefCell<GraphData>>)
+    Graph(Arc<GraphData>),
+    List(Vec<DataValue>),
+    Map(BTreeMap<String, DataValue>)
 }
 
 impl From<()> for DataValue{
@@ -281,18 +1555,16 @@ impl From<i32> for DataValue{
 // hack around cycle caused by () impl'ing Into<String>
 pub trait ShouldDataValueFromString{}
 impl     ShouldDataValueFromString for String{}
-impl<'a> ShouldDataValueFromString for &'a str{}
+impl ShouldDataValueFromString for &str{}
 impl<S> From<S> for DataValue where S: Into<String> + ShouldDataValueFromString{
     fn from(val: S) -> DataValue{
         DataValue::String(val.into())
     }
 }
 
-impl From<Rc<RefCell<Graph>>> for DataValue{
-    fn from(val: Rc<RefCell<Graph>>) -> Self{
-        let v = val.clone();
-        let v = v.borrow();
-        DataValue::Graph(v.data.clone())
+impl<'a> From<&'a Graph> for DataValue{
+    fn from(val: &'a Graph) -> Self{
+        DataValue::Graph(Arc::new(val.data.read().unwrap().clone()))
     }
 }
 
@@ -301,7 +1573,13 @@ impl From<Rc<RefCell<Graph>>> for DataValue{
 pub enum Response{
     Ok,
     Warn{val: DataValue},
-    Err {val: DataValue}
+    Err {val: DataValue},
+    LinkTargets{source: PortId, targets: Vec<PortId>},
+    // empty if the graph has no cycles
+    Cycle{nodes: Vec<NodeId>},
+    // answers every attach whether or not acks were asked for, so that a backend that had a
+    // graph made for it learns which one it is
+    Attached{id: GraphId}
 }
 
 #[allow(non_snake_case)]
@@ -313,3 +1591,409 @@ impl Response{
         Response::Err{ val }
     }
 }
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+
+    fn port(kind: &str, data_type: Option<&str>, max_links: Option<usize>) -> Node{
+        let spec = PortSpec{ data_type: data_type.map(|t| t.into()), max_links };
+        match kind{
+            "in"  => Node::InPort(spec),
+            "out" => Node::OutPort(spec),
+            _     => Node::BiPort(spec)
+        }
+    }
+
+    fn path(ids: &[&str]) -> NodePath{
+        ids.iter().map(|&id| id.to_string()).collect()
+    }
+
+    // a -> b -> c, with ports named after their node and direction
+    fn chain() -> Graph{
+        let graph = Graph::default();
+        for (id, ports) in [("a", vec!["a_out"]), ("b", vec!["b_in", "b_out"]), ("c", vec!["c_in"])]{
+            let nodes = ports
+                .into_iter()
+                .map(|p| (p.to_string(), port(if p.ends_with("in"){ "in" } else { "out" }, None, None)))
+                .collect();
+            assert_eq!(graph.add_node(&path(&[id]), &Node::Labelled{ data: id.into(), nodes }), Response::Ok);
+        }
+        assert_eq!(graph.add_link(&"a_out".into(), &"b_in".into()), Response::Ok);
+        assert_eq!(graph.add_link(&"b_out".into(), &"c_in".into()), Response::Ok);
+        graph
+    }
+
+    #[test]
+    fn del_link_removes_the_link_and_prunes_the_source(){
+        let graph = chain();
+        assert_eq!(graph.del_link(&"a_out".into(), &"b_in".into()), Response::Ok);
+        let data = graph.data.read().unwrap();
+        assert!(!data.has_link(&"a_out".into(), &"b_in".into()));
+        assert!(!data.links.contains_key("a_out"));
+        assert!(data.has_link(&"b_out".into(), &"c_in".into()));
+    }
+
+    #[test]
+    fn del_link_warns_about_missing_links(){
+        let graph = chain();
+        match graph.del_link(&"a_out".into(), &"c_in".into()){
+            Response::Warn{ .. } => {},
+            other => panic!("expected a warning, got {:?}", other)
+        }
+        assert_eq!(graph.data.read().unwrap().links.len(), 2);
+    }
+
+    fn is_err(response: &Response) -> bool{
+        matches!(*response, Response::Err{ .. })
+    }
+
+    fn is_warning(response: &Response) -> bool{
+        matches!(*response, Response::Warn{ .. })
+    }
+
+    #[test]
+    fn insert_and_remove_nested_nodes(){
+        let mut data = GraphData::default();
+        data.insert_node(&path(&["l"]), Node::Labelled{ data: "l".into(), nodes: BTreeMap::new() }).unwrap();
+        data.insert_node(&path(&["l", "c"]), Node::Container{ nodes: vec![] }).unwrap();
+        data.insert_node(&path(&["l", "c", "0"]), Node::Knob{ data: "k".into() }).unwrap();
+        data.insert_node(&path(&["l", "c", "0"]), Node::Button{ data: "b".into() }).unwrap();
+        assert_eq!(data.node(&path(&["l", "c", "1"])), Some(&Node::Knob{ data: "k".into() }));
+        assert!(data.insert_node(&path(&["l", "c", "5"]), Node::Knob{ data: "k".into() }).is_err());
+        assert!(data.insert_node(&path(&["l", "c"]), Node::Container{ nodes: vec![] }).is_err());
+        assert!(data.insert_node(&path(&["missing", "x"]), Node::Knob{ data: "k".into() }).is_err());
+
+        assert_eq!(data.remove_node(&path(&["l", "c", "0"])).unwrap(), Node::Button{ data: "b".into() });
+        assert_eq!(data.node(&path(&["l", "c", "0"])), Some(&Node::Knob{ data: "k".into() }));
+        assert!(data.remove_node(&path(&["l", "c", "1"])).is_err());
+        assert!(data.remove_node(&[]).is_err());
+    }
+
+    #[test]
+    fn move_node_keeps_links(){
+        let graph = chain();
+        graph.add_node(&path(&["d"]), &Node::Labelled{ data: "d".into(), nodes: BTreeMap::new() });
+        assert_eq!(graph.move_node(&path(&["b", "b_in"]), &path(&["d", "b_in"])), Response::Ok);
+        let data = graph.data.read().unwrap();
+        assert!(data.node(&path(&["b", "b_in"])).is_none());
+        assert!(data.node(&path(&["d", "b_in"])).is_some());
+        assert!(data.has_link(&"a_out".into(), &"b_in".into()));
+        assert!(data.check_links().is_empty());
+    }
+
+    #[test]
+    fn move_node_refuses_to_rename_ports(){
+        let graph = chain();
+        let before = graph.data.read().unwrap().clone();
+        assert!(is_err(&graph.move_node(&path(&["b", "b_in"]), &path(&["b", "renamed"]))));
+        assert!(is_err(&graph.move_node(&path(&["b", "b_in"]), &path(&["missing", "b_in"]))));
+        assert!(is_err(&graph.move_node(&path(&["missing"]), &path(&["elsewhere"]))));
+        assert_eq!(*graph.data.read().unwrap(), before);
+    }
+
+    #[test]
+    fn move_node_refuses_to_shift_container_ports(){
+        let graph = Graph::default();
+        graph.add_node(&path(&["c"]), &Node::Container{
+            nodes: vec![Node::Knob{ data: "k".into() }, port("in", None, None)]
+        });
+        let before = graph.data.read().unwrap().clone();
+        assert!(is_err(&graph.move_node(&path(&["c", "0"]), &path(&["k"]))));
+        assert_eq!(*graph.data.read().unwrap(), before);
+        // nothing with ports comes after the last child
+        assert_eq!(graph.move_node(&path(&["c", "1"]), &path(&["c", "1"])), Response::Ok);
+    }
+
+    #[test]
+    fn inserts_and_removals_refuse_to_shift_container_ports(){
+        let graph = chain();
+        graph.add_node(&path(&["c", "box"]), &Node::Container{
+            nodes: vec![Node::Knob{ data: "k".into() }, port("in", None, None)]
+        });
+        assert_eq!(graph.add_link(&"a_out".into(), &"box/1".into()), Response::Ok);
+        let before = graph.data.read().unwrap().clone();
+        assert!(is_err(&graph.add_node(&path(&["c", "box", "0"]), &Node::Knob{ data: "j".into() })));
+        assert!(is_err(&graph.add_node(&path(&["c", "box", "1"]), &Node::Knob{ data: "j".into() })));
+        assert!(is_err(&graph.remove_node(&path(&["c", "box", "0"]))));
+        assert!(graph.data.write().unwrap().remove_node(&path(&["c", "box", "0"])).is_err());
+        assert_eq!(*graph.data.read().unwrap(), before);
+
+        // nothing with ports comes after the end or the last child
+        assert_eq!(graph.add_node(&path(&["c", "box", "2"]), &Node::Knob{ data: "j".into() }), Response::Ok);
+        assert_eq!(graph.remove_node(&path(&["c", "box", "2"])), Response::Ok);
+        assert_eq!(graph.remove_node(&path(&["c", "box", "1"])), Response::Ok);
+        assert!(!graph.data.read().unwrap().has_link(&"a_out".into(), &"box/1".into()));
+        assert_eq!(graph.add_node(&path(&["c", "box", "0"]), &Node::Knob{ data: "j".into() }), Response::Ok);
+    }
+
+    #[test]
+    fn container_ports_are_qualified(){
+        let mut data = GraphData::default();
+        data.insert_node(&path(&["x"]), Node::Container{ nodes: vec![port("out", None, None)] }).unwrap();
+        data.insert_node(&path(&["y"]), Node::Container{ nodes: vec![port("in", None, None)] }).unwrap();
+        let ports: Vec<PortId> = data.port_map().keys().cloned().collect();
+        assert_eq!(ports, vec!["x/0".to_string(), "y/0".to_string()]);
+        assert_eq!(data.port_id(&path(&["y", "0"])), Some("y/0".into()));
+        assert_eq!(data.check_link(&"x/0".into(), &"y/0".into()), Ok(()));
+    }
+
+    #[test]
+    fn check_link_rejects_bad_links(){
+        let mut data = GraphData::default();
+        let nodes = vec![("o",  port("out", Some("num"), None)),
+                         ("i",  port("in",  Some("num"), Some(1))),
+                         ("s",  port("in",  Some("str"), None)),
+                         ("i2", port("in",  None,        None))]
+            .into_iter()
+            .map(|(id, node)| (id.to_string(), node))
+            .collect();
+        data.insert_node(&path(&["n"]), Node::Labelled{ data: "n".into(), nodes }).unwrap();
+
+        assert_eq!(data.check_link(&"o".into(), &"i".into()), Ok(()));
+        assert_eq!(data.check_link(&"o".into(), &"i2".into()), Ok(()));
+        assert_eq!(data.check_link(&"o".into(), &"s".into()),
+                   Err(LinkError::Incompatible{ source: "o".into(), target: "s".into() }));
+        assert_eq!(data.check_link(&"i".into(), &"o".into()),
+                   Err(LinkError::Direction{ source: "i".into(), target: "o".into() }));
+        assert_eq!(data.check_link(&"o".into(), &"nope".into()),
+                   Err(LinkError::UnknownPort("nope".into())));
+
+        data.links.entry("o".into()).or_insert_with(BTreeSet::new).insert("i".into());
+        // the existing link still checks out, another one doesn't fit
+        assert_eq!(data.check_link(&"o".into(), &"i".into()), Ok(()));
+        data.insert_node(&path(&["o2"]), port("out", Some("num"), None)).unwrap();
+        assert_eq!(data.check_link(&"o2".into(), &"i".into()),
+                   Err(LinkError::Full{ port: "i".into(), max_links: 1 }));
+        assert!(data.check_links().is_empty());
+    }
+
+    #[test]
+    fn undo_and_redo_restore_the_graph(){
+        let graph = chain();
+        // undoing SetData can't unset an id, so it needs a value beforehand
+        graph.data.write().unwrap().data.insert("x".into(), DataValue::from(0));
+        let start = graph.data.read().unwrap().clone();
+        let edits = [
+            Command::DelLink{ source: "a_out".into(), target: "b_in".into() },
+            Command::SetData{ id: "x".into(), value: DataValue::from(1) },
+            Command::RemoveNode{ path: path(&["b"]) },
+            Command::AddNode{ path: path(&["e"]), node: port("in", None, None) },
+            Command::AddLink{ source: "a_out".into(), target: "e".into() },
+            Command::ReplaceNode{ path: path(&["e"]), node: Node::Knob{ data: "k".into() } }
+        ];
+        let mut states = vec![start.clone()];
+        for edit in edits.iter(){
+            assert_eq!(graph.edit(edit), Response::Ok, "{:?}", edit);
+            states.push(graph.data.read().unwrap().clone());
+        }
+        for state in states.iter().rev().skip(1){
+            assert_eq!(graph.undo(), Response::Ok);
+            assert_eq!(*graph.data.read().unwrap(), *state);
+        }
+        assert!(is_warning(&graph.undo()));
+        for state in states.iter().skip(1){
+            assert_eq!(graph.redo(), Response::Ok);
+            assert_eq!(*graph.data.read().unwrap(), *state);
+        }
+        assert!(is_warning(&graph.redo()));
+    }
+
+    #[test]
+    fn undo_reverts_moves(){
+        let graph = chain();
+        graph.add_node(&path(&["d"]), &Node::Labelled{ data: "d".into(), nodes: BTreeMap::new() });
+        let start = graph.data.read().unwrap().clone();
+        assert_eq!(graph.edit(&Command::MoveNode{ path: path(&["b", "b_in"]), to: path(&["d", "b_in"]) }),
+                   Response::Ok);
+        graph.undo();
+        assert_eq!(*graph.data.read().unwrap(), start);
+    }
+
+    #[test]
+    fn failed_edits_are_not_recorded(){
+        let graph = chain();
+        assert!(is_err(&graph.edit(&Command::RemoveNode{ path: path(&["missing"]) })));
+        assert!(is_warning(&graph.undo()));
+    }
+
+    #[test]
+    fn failed_undos_change_nothing(){
+        let graph = chain();
+        assert_eq!(graph.edit(&Command::RemoveNode{ path: path(&["b"]) }), Response::Ok);
+        // a backend takes away c, so relinking b_out -> c_in after putting b back fails
+        assert_eq!(graph.apply_unrecorded(&Command::RemoveNode{ path: path(&["c"]) }, ClientType::Backend),
+                   Response::Ok);
+        let before = graph.data.read().unwrap().clone();
+        let seq    = graph.log.lock().unwrap().last();
+
+        assert!(is_err(&graph.undo()));
+        assert_eq!(*graph.data.read().unwrap(), before);
+        assert_eq!(graph.log.lock().unwrap().last(), seq, "a partial undo was broadcast");
+        assert!(is_warning(&graph.redo()));
+        assert!(is_err(&graph.undo()), "the edit should still be there to undo");
+    }
+
+    #[test]
+    fn update_log_since(){
+        let mut log = UpdateLog::new(1);
+        assert_eq!(log.last(), 1 << 32);
+        let first = log.push(ClientType::Frontend, Response::Ok.into());
+        let _     = log.push(ClientType::Backend,  Response::Ok.into());
+        let third = log.push(ClientType::Both,     Response::Ok.into());
+        assert_eq!(first, (1 << 32) + 1);
+
+        let seqs = |updates: Vec<Sequenced>| updates.into_iter().map(|u| u.seq).collect::<Vec<_>>();
+        assert_eq!(seqs(log.since(&ClientType::Frontend, first - 1).unwrap()), vec![first, third]);
+        assert_eq!(seqs(log.since(&ClientType::Frontend, first).unwrap()), vec![third]);
+        assert!(log.since(&ClientType::Frontend, third).unwrap().is_empty());
+        // from the future, e.g. another run
+        assert!(log.since(&ClientType::Frontend, third + 1).is_none());
+        assert!(UpdateLog::new(2).since(&ClientType::Frontend, third).is_none());
+
+        for _ in 0..UPDATE_LOG_LIMIT{
+            log.push(ClientType::Frontend, Response::Ok.into());
+        }
+        assert!(log.since(&ClientType::Frontend, first).is_none());
+        assert!(log.since(&ClientType::Frontend, log.last() - 1).is_some());
+    }
+
+    #[test]
+    fn attaching_never_sees_an_edit_twice(){
+        let graph  = Graph::default();
+        let editor = {
+            let graph = graph.clone();
+            ::std::thread::spawn(move ||
+                for i in 0..20000{
+                    let command = Command::SetData{ id: format!("k{}", i), value: DataValue::Int(i) };
+                    assert_eq!(graph.edit(&command), Response::Ok);
+                })
+        };
+        // attach the way a frontend does: every edit sets a new id and is logged as the next seq,
+        // so the graph as of the last logged update holds exactly that many values
+        while !editor.is_finished(){
+            let log = graph.log.lock().unwrap();
+            assert_eq!(graph.data.read().unwrap().data.len() as Seq, log.last(),
+                       "an edit is in the graph but not yet logged");
+        }
+        editor.join().unwrap();
+    }
+
+    #[test]
+    fn cycles_and_order(){
+        let graph = chain();
+        let (order, unordered) = graph.data.read().unwrap().partial_order();
+        assert_eq!(order, path(&["a", "b", "c"]));
+        assert!(unordered.is_empty());
+        assert_eq!(graph.data.read().unwrap().topological_order(), Ok(path(&["a", "b", "c"])));
+        assert_eq!(graph.data.read().unwrap().find_cycle(), None);
+
+        graph.add_node(&path(&["c", "c_out"]), &port("out", None, None));
+        graph.add_node(&path(&["b", "b_in2"]), &port("in", None, None));
+        graph.add_link(&"c_out".into(), &"b_in2".into());
+        let data = graph.data.read().unwrap();
+        assert_eq!(data.find_cycle(), Some(path(&["b", "c", "b"])));
+        assert_eq!(data.topological_order(), Err(path(&["b", "c", "b"])));
+        let (order, unordered) = data.partial_order();
+        assert_eq!(order, path(&["a"]));
+        assert_eq!(unordered, path(&["b", "c"]));
+    }
+
+    #[test]
+    fn long_chains_dont_overflow(){
+        let mut data = GraphData::default();
+        let n = 50000;
+        for i in 0..n{
+            let nodes = vec![(format!("{}_in", i),  port("in",  None, None)),
+                             (format!("{}_out", i), port("out", None, None))]
+                .into_iter()
+                .collect();
+            data.nodes.insert(format!("{:06}", i), Node::Labelled{ data: "n".into(), nodes });
+            if i > 0{
+                data.links.entry(format!("{}_out", i - 1)).or_insert_with(BTreeSet::new).insert(format!("{}_in", i));
+            }
+        }
+        assert_eq!(data.find_cycle(), None);
+        data.links.entry(format!("{}_out", n - 1)).or_insert_with(BTreeSet::new).insert("0_in".into());
+        assert_eq!(data.find_cycle().map(|cycle| cycle.len()), Some(n + 1));
+    }
+
+    fn round_trip<T>(val: &T) where T: Serialize + for<'de> Deserialize<'de> + PartialEq + fmt::Debug{
+        for codec in &[Codec::Json, Codec::MsgPack]{
+            let decoded: T = match codec.encode(val).unwrap(){
+                WsMessage::Text(t)   => serde_json::from_str(&t).unwrap(),
+                WsMessage::Binary(b) => rmp_serde::from_slice(&b).unwrap()
+            };
+            assert_eq!(decoded, *val, "{:?}", codec);
+        }
+    }
+
+    fn commands() -> Vec<Command>{
+        let mut graph = GraphData::default();
+        graph.insert_node(&path(&["n"]), port("in", Some("num"), Some(2))).unwrap();
+        graph.data.insert("x".into(), DataValue::List(vec![DataValue::from(1), DataValue::from("s")]));
+        vec![Command::AddLink{ source: "a".into(), target: "b".into() },
+             Command::DelLink{ source: "a".into(), target: "b".into() },
+             Command::LinkTargets{ source: "a".into() },
+             Command::FindCycle,
+             Command::Undo,
+             Command::Redo,
+             Command::SetData{ id: "x".into(), value: DataValue::Float(0.5) },
+             Command::SetGraph{ graph: Arc::new(graph) },
+             Command::AddNode{ path: path(&["k"]), node: Node::Knob{ data: "k".into() } },
+             Command::RemoveNode{ path: path(&["k"]) },
+             Command::MoveNode{ path: path(&["k"]), to: path(&["l"]) },
+             Command::ReplaceNode{ path: path(&["l"]), node: Node::Container{ nodes: vec![] } },
+             Command::FrontendAttach{ id: 1, codec: Some(Codec::MsgPack), acks: true, since: Some(5) },
+             Command::BackendAttach{ id: None, codec: None, acks: false }]
+    }
+
+    fn responses() -> Vec<Response>{
+        vec![Response::Ok,
+             Response::Warn{ val: "careful".into() },
+             Response::Err{ val: DataValue::Nil },
+             Response::LinkTargets{ source: "a".into(), targets: vec!["b".into(), "c".into()] },
+             Response::Cycle{ nodes: path(&["a", "b", "a"]) },
+             Response::Attached{ id: 3 }]
+    }
+
+    #[test]
+    fn messages_round_trip_with_both_codecs(){
+        for command in commands(){
+            round_trip(&Envelope::from(command.clone()));
+            round_trip(&Envelope{ req: Some(7), command: command.clone() });
+            round_trip(&Update::from(command));
+        }
+        for response in responses(){
+            round_trip(&Reply::from(response.clone()));
+            round_trip(&Reply{ req: Some(7), response: response.clone() });
+            round_trip(&Update::from(Reply{ req: Some(8), response }));
+        }
+        round_trip(&GraphList{ list: vec![1, 2] });
+    }
+
+    #[test]
+    fn request_ids_sit_next_to_the_tag(){
+        let envelope = Envelope{ req: Some(3), command: Command::LinkTargets{ source: "a".into() } };
+        assert_eq!(serde_json::to_string(&envelope).unwrap(),
+                   r#"{"req":3,"_":"LinkTargets","source":"a"}"#);
+        assert_eq!(serde_json::to_string(&Reply::from(Response::Ok)).unwrap(), r#"{"_":"Ok"}"#);
+        let envelope: Envelope = serde_json::from_str(r#"{"_":"Undo","req":4}"#).unwrap();
+        assert_eq!(envelope, Envelope{ req: Some(4), command: Command::Undo });
+        let envelope: Envelope = serde_json::from_str(r#"{"_":"Undo","req":null}"#).unwrap();
+        assert_eq!(envelope, Envelope::from(Command::Undo));
+    }
+
+    #[test]
+    fn sequenced_updates_round_trip_with_both_codecs(){
+        for command in commands(){
+            round_trip(&Sequenced{ seq: (1 << 32) + 1, update: command.into() });
+        }
+        for response in responses(){
+            round_trip(&Sequenced{ seq: 2, update: Reply{ req: Some(3), response }.into() });
+        }
+        let sequenced = Sequenced{ seq: 9, update: Command::Undo.into() };
+        assert_eq!(serde_json::to_string(&sequenced).unwrap(), r#"{"seq":9,"Command":{"_":"Undo"}}"#);
+        assert!(serde_json::from_str::<Sequenced>(r#"{"Command":{"_":"Undo"}}"#).is_err());
+    }
+}
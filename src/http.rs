@@ -1,20 +1,55 @@
-use hyper::server::{Http, Request, Response, Service, NewService};
+use hyper::server::{Http, Request, Response, Service};
 use hyper::Error;
-use futures::Future;
+use futures::{Future, Stream, Poll, Async};
+use futures::sync::oneshot;
+use tokio_core::reactor::{Core, Handle, Timeout};
+use tokio_core::net::{TcpListener, TcpStream};
+use tokio_io::{AsyncRead, AsyncWrite};
+use tokio_io::io as async_io;
 
+use std::cmp::min;
 use std::thread;
 use std::thread::{JoinHandle};
 use std::io;
+use std::io::{Read, Write};
+use std::mem;
+use std::net::{Shutdown, SocketAddr};
+use std::rc::Rc;
+use std::sync::mpsc::channel;
+use std::time::Duration;
 use time;
 
-use file::FileServer;
+use api::{Api, API_PATH};
+use graph::GraphStore;
+use file::{FileServer, FileOptions};
+use websocket::bound;
 use filecache::FileCache;
 
-type ResponseFuture = Box<Future<Item=Response, Error=Error>>;
+type ResponseFuture = Box<dyn Future<Item=Response, Error=Error>>;
+
+// requests with longer heads go to hyper, which rejects them
+static MAX_HEAD_SIZE: usize = 8192;
+// connections that haven't sent a whole request head by then are dropped
+const HEAD_TIMEOUT_MS: u64 = 10000;
 
 #[derive(Clone)]
 struct MainService{
-    file: FileServer
+    file: FileServer,
+    api:  Api
+}
+
+impl MainService{
+    fn new(files: FileOptions, cache: FileCache, store: GraphStore) -> MainService{
+        MainService{
+            file: FileServer::new(files, cache),
+            api:  Api::new(store)
+        }
+    }
+}
+
+// only API_PATH itself and what's below it, not every path that happens to start the same way
+fn is_api(path: &str) -> bool{
+    path.starts_with(API_PATH) && matches!(path.as_bytes().get(API_PATH.len()), None | Some(&b'/'))
 }
 
 impl Service for MainService {
@@ -24,48 +59,279 @@ impl Service for MainService {
     type Future   = ResponseFuture;
 
     fn call(&self, req: Request) -> Self::Future {
-        profile!(format!("{}", req.path()), {self.file.call(req)})
+        profile!(format!("{}", req.path()), {
+            if is_api(req.path()){
+                self.api.call(req)
+            }
+            else{
+                self.file.call(req)
+            }
+        })
     }
 }
 
-struct ServiceFactory{
-    proto: MainService
+/// Paths that websocket upgrades are accepted on, along with the address of the websocket server
+/// that handles them.
+pub type Upgrades = Vec<(String, SocketAddr)>;
+
+/// The parts of a request head needed to route it.
+struct Head{
+    path:    String,
+    upgrade: bool
+}
+
+fn head_len(buf: &[u8]) -> Option<usize>{
+    buf.windows(4).position(|w| w == b"\r\n\r\n").map(|i| i + 4)
 }
 
-impl ServiceFactory{
-    fn new(cache: FileCache)
-        -> ServiceFactory {
-        ServiceFactory {
-            proto:
-                MainService{
-                    file: FileServer::new("client/".into(), cache),
-                }
+fn parse_head(head: &[u8]) -> Option<Head>{
+    let head  = ::std::str::from_utf8(head).ok()?;
+    let mut lines = head.split("\r\n");
+    let path  = lines.next()?.split(' ').nth(1)?;
+    let path  = path.split('?').next()?;
+    let mut upgrade = false;
+    for line in lines{
+        let mut parts = line.splitn(2, ':');
+        let name  = parts.next()?.trim();
+        let value = parts.next().unwrap_or("").trim();
+        if name.eq_ignore_ascii_case("upgrade"){
+            upgrade = value.eq_ignore_ascii_case("websocket");
         }
     }
+    Some(Head{ path: path.into(), upgrade })
 }
 
-impl NewService for ServiceFactory{
-    type Request = Request;
-    type Response = Response;
-    type Error = Error;
-    type Instance = MainService;
+// `buf` with any X-Forwarded-For the client sent replaced by the address it connected from, so
+// the websocket server can't be told someone else is talking to it
+fn forwarded_for(buf: &[u8], peer: SocketAddr) -> Vec<u8>{
+    let len  = head_len(buf).unwrap_or(buf.len());
+    let head = String::from_utf8_lossy(&buf[..len]);
+    let mut lines: Vec<String> = Vec::new();
+    for (i, line) in head.split("\r\n").enumerate(){
+        let name = line.split(':').next().unwrap_or("").trim();
+        if i > 0 && name.eq_ignore_ascii_case("x-forwarded-for"){
+            continue;
+        }
+        lines.push(line.into());
+        if i == 0{
+            lines.push(format!("X-Forwarded-For: {}", peer.ip()));
+        }
+    }
+    let mut forwarded = lines.join("\r\n").into_bytes();
+    forwarded.extend_from_slice(&buf[len..]);
+    forwarded
+}
+
+/// Reads from a connection until the end of the first request head, or until MAX_HEAD_SIZE bytes
+/// or the end of the stream have been read. Fails once `timeout` fires.
+struct ReadHead{
+    stream:  Option<TcpStream>,
+    buf:     Vec<u8>,
+    timeout: Timeout
+}
+
+impl Future for ReadHead{
+    type Item  = (TcpStream, Vec<u8>);
+    type Error = io::Error;
+
+    fn poll(&mut self) -> Poll<Self::Item, io::Error>{
+        loop{
+            if head_len(&self.buf).is_some() || self.buf.len() >= MAX_HEAD_SIZE{
+                break;
+            }
+            let mut chunk = [0u8; 1024];
+            let n = match self.stream.as_mut().unwrap().read(&mut chunk){
+                Ok(n) => n,
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
+                    if self.timeout.poll()?.is_ready(){
+                        return Err(io::Error::new(io::ErrorKind::TimedOut,
+                                                  "no request head in time"));
+                    }
+                    return Ok(Async::NotReady)
+                },
+                Err(e) => return Err(e)
+            };
+            if n == 0{
+                break;
+            }
+            self.buf.extend_from_slice(&chunk[..n]);
+        }
+        Ok(Async::Ready((self.stream.take().unwrap(), mem::take(&mut self.buf))))
+    }
+}
+
+/// A connection that yields the bytes already read from it before reading any more.
+struct Prefixed{
+    prefix: Vec<u8>,
+    pos:    usize,
+    stream: TcpStream
+}
+
+impl Read for Prefixed{
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize>{
+        if self.pos < self.prefix.len(){
+            let n = min(buf.len(), self.prefix.len() - self.pos);
+            buf[..n].copy_from_slice(&self.prefix[self.pos..self.pos + n]);
+            self.pos += n;
+            Ok(n)
+        }
+        else{
+            self.stream.read(buf)
+        }
+    }
+}
+
+impl Write for Prefixed{
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize>{
+        self.stream.write(buf)
+    }
+    fn flush(&mut self) -> io::Result<()>{
+        self.stream.flush()
+    }
+}
+
+impl AsyncRead for Prefixed{}
 
-    fn new_service(&self) -> Result<Self::Instance, io::Error>{
-        Ok(self.proto.clone())
+impl AsyncWrite for Prefixed{
+    fn shutdown(&mut self) -> Poll<(), io::Error>{
+        AsyncWrite::shutdown(&mut self.stream)
     }
 }
 
-pub fn launch_thread(cache: FileCache)
-    -> JoinHandle<()>{
-    thread::Builder::new()
+/// One direction of a proxied connection. tokio-core's `shutdown` for a `TcpStream` doesn't touch
+/// the socket, so without this a half-close would never reach the other side.
+struct Half(Rc<TcpStream>);
+
+impl Read for Half{
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize>{
+        (&*self.0).read(buf)
+    }
+}
+
+impl Write for Half{
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize>{
+        (&*self.0).write(buf)
+    }
+    fn flush(&mut self) -> io::Result<()>{
+        (&*self.0).flush()
+    }
+}
+
+impl AsyncRead for Half{}
+
+impl AsyncWrite for Half{
+    fn shutdown(&mut self) -> Poll<(), io::Error>{
+        self.0.shutdown(Shutdown::Write).map(Async::Ready)
+    }
+}
+
+// passes the upgrade request on to the websocket server and then relays bytes both ways
+// until both sides are done. ws has no way to take over a socket that was accepted elsewhere, so
+// the upgrade goes over loopback instead of straight to the websocket server's handlers
+fn proxy(handle: &Handle, client: TcpStream, head: Vec<u8>, peer: SocketAddr,
+         target: SocketAddr)
+    -> Box<dyn Future<Item=(), Error=io::Error>>
+{
+    // lets the websocket server know who it's really talking to
+    let head = forwarded_for(&head, peer);
+    Box::new(TcpStream::connect(&target, handle)
+        .and_then(move |server| async_io::write_all(server, head))
+        .and_then(move |(server, _)|{
+            let (client, server) = (Rc::new(client), Rc::new(server));
+            let (client_read, client_write) = (Half(client.clone()), Half(client));
+            let (server_read, server_write) = (Half(server.clone()), Half(server));
+            let upstream = async_io::copy(client_read, server_write)
+                .and_then(|(_, _, server_write)| async_io::shutdown(server_write));
+            let downstream = async_io::copy(server_read, client_write)
+                .and_then(|(_, _, client_write)| async_io::shutdown(client_write));
+            upstream.join(downstream).map(|_| ())
+        }))
+}
+
+fn route(handle: &Handle, http: &Http, service: &MainService, upgrades: &Rc<Upgrades>,
+         stream: TcpStream, peer: SocketAddr)
+{
+    let conn     = handle.clone();
+    let http     = http.clone();
+    let service  = service.clone();
+    let upgrades = upgrades.clone();
+    let timeout = match Timeout::new(Duration::from_millis(HEAD_TIMEOUT_MS), handle){
+        Ok(timeout) => timeout,
+        Err(e)      => {
+            debug!("{:>20} - couldn't time the request head: {:?}", peer, e);
+            return;
+        }
+    };
+    let read = ReadHead{ stream: Some(stream), buf: Vec::new(), timeout };
+    let routed = read.and_then(move |(stream, buf)|{
+        let len  = head_len(&buf).unwrap_or(buf.len());
+        let head = parse_head(&buf[..len]);
+        let target = match head{
+            Some(ref head) if head.upgrade =>
+                upgrades.iter()
+                        .find(|(path, _)| path == &head.path)
+                        .map(|&(_, target)| target),
+            _ => None
+        };
+        let routed: Box<dyn Future<Item=(), Error=io::Error>> = match (target, head){
+            (Some(target), Some(head)) => {
+                trace!("{:>20} - upgrading {} through {}", peer, head.path, target);
+                proxy(&conn, stream, buf, peer, target)
+            },
+            // hyper can't hand over a connection once it's upgraded, so a keep-alive connection
+            // can't switch to a websocket later
+            _ => {
+                #[allow(deprecated)] // hyper 0.11 has no other way to serve an accepted socket
+                http.bind_connection(&conn, Prefixed{ prefix: buf, pos: 0, stream }, peer, service);
+                Box::new(::futures::future::ok(()))
+            }
+        };
+        routed
+    });
+    handle.spawn(routed.map_err(move |e| debug!("{:>20} - connection failed: {:?}", peer, e)));
+}
+
+/// Serves files as `files` says on `addr` until something is sent on the returned Sender, along
+/// with a REST API for the graphs in `store`.
+/// Websocket upgrades on the paths in `upgrades` are passed on to their websocket servers so
+/// that everything is reachable through `addr`.
+/// Also returns the address actually bound, which differs from `addr` if its port is 0.
+pub fn launch_thread(addr: SocketAddr, files: FileOptions, cache: FileCache,
+                     store: GraphStore, upgrades: Upgrades)
+    -> io::Result<(JoinHandle<()>, oneshot::Sender<()>, SocketAddr)>{
+    let (shutdown_tx, shutdown_rx) = oneshot::channel();
+    let (addr_tx, addr_rx) = channel();
+    let handle = thread::Builder::new()
         .name("HTTP".into())
         .spawn(move ||{
-    let addr_string = "127.0.0.1:3000";
-    let addr        = addr_string.parse().unwrap();
-    let factory     = ServiceFactory::new(cache);
-    let server      = Http::new().bind(&addr, factory).unwrap();
-
-    info!("Starting server on http://{}", addr_string);
-    server.run().unwrap();
-    }).unwrap()
+    let bound = Core::new().and_then(|core|{
+        let listener = TcpListener::bind(&addr, &core.handle())?;
+        let addr     = listener.local_addr()?;
+        Ok((core, listener, addr))
+    });
+    let (mut core, listener, addr) = match bound{
+        Ok(bound) => bound,
+        Err(e)    => {
+            let _ = addr_tx.send(Err(e));
+            return;
+        }
+    };
+    let _ = addr_tx.send(Ok(addr));
+    let handle      = core.handle();
+
+    let http        = Http::new();
+    let service     = MainService::new(files, cache, store);
+    let upgrades    = Rc::new(upgrades);
+    let server      = listener.incoming().for_each(move |(stream, peer)|{
+        route(&handle, &http, &service, &upgrades, stream, peer);
+        Ok(())
+    });
+
+    info!("Starting server on http://{}", addr);
+    // dropping the Sender also cancels, which shuts down too
+    let shutdown = shutdown_rx.then(|_| Ok(()));
+    core.run(server.select(shutdown).map(|_| ()).map_err(|(e, _)| e)).unwrap();
+    info!("Stopped server on http://{}", addr);
+    })?;
+    Ok((handle, shutdown_tx, bound(addr_rx)?))
 }
@@ -0,0 +1,105 @@
+#![cfg_attr(test, feature(test))]
+#![allow(dead_code)]
+extern crate notify;
+extern crate serde;
+extern crate serde_json;
+extern crate rmp_serde;
+//extern crate rmpv;
+extern crate ws;
+extern crate url;
+extern crate hyper;
+extern crate tokio_core;
+extern crate tokio;
+extern crate tokio_io;
+extern crate futures;
+extern crate futures_cpupool;
+extern crate bytes;
+extern crate subprocess;
+extern crate regex;
+extern crate time;
+extern crate toml;
+extern crate flate2;
+extern crate brotli;
+
+#[macro_use]
+extern crate serde_derive;
+
+#[macro_use]
+extern crate lazy_static;
+
+#[macro_use]
+extern crate log;
+
+macro_rules! profile {
+    ($name:expr, $bl:block) => ({
+        use log::Level::Trace;
+        if log_enabled!(Trace){
+            let name   = $name;
+            let start  = time::PreciseTime::now();
+            let result = $bl;
+            trace!("{} took {:?}", name, start.to(time::PreciseTime::now()));
+            result
+        }
+        else{
+            $bl
+        }
+    })
+}
+
+pub mod graph;
+mod wire;
+pub mod persist;
+pub mod eval;
+pub mod backend;
+pub mod config;
+mod rebuilder;
+mod websocket;
+mod http;
+mod api;
+mod file;
+mod mimetypes;
+mod filecache;
+mod filethread;
+mod reloader;
+mod server;
+
+pub use server::{Builder, Server, Shutdown, GRAPH_PATH, RELOAD_PATH};
+pub use config::{Config, ConfigError};
+pub use filecache::CacheStats;
+
+#[cfg(test)]
+mod tests{
+    extern crate test;
+    use super::*;
+    use self::test::Bencher;
+    use std::sync::{Arc, atomic::{AtomicBool, Ordering}};
+    
+    #[bench]
+    fn bench_roundrobin(b: &mut Bencher){
+        let rr = Arc::new(filethread::RoundRobin::new(8));
+        
+        b.iter(|| rr.get_next() );
+    }
+
+    #[bench]
+    fn bench_crowded_roundrobin(b: &mut Bencher){
+        let rr = Arc::new(filethread::RoundRobin::new(8));
+        let running =  Arc::new(AtomicBool::new(true));
+        let threads = (0..32).map(|_|{
+            let r = rr.clone();
+            let running = running.clone();
+            std::thread::spawn(move ||
+                while running.load(Ordering::Relaxed){
+                    r.get_next();
+                })
+        }).collect::<Vec<std::thread::JoinHandle<_>>>();
+
+        b.iter(|| rr.get_next() );
+
+        running.store(false, Ordering::Relaxed);
+        for t in threads{
+            t.join().unwrap();
+        }
+    } 
+
+}
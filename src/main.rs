@@ -1,96 +1,14 @@
-#![feature(box_syntax)]
-#![feature(conservative_impl_trait)]
-#![feature(try_trait)]
-#![feature(test)]
-#![allow(dead_code)]
-extern crate notify;
-extern crate serde;
-extern crate serde_json;
-//extern crate rmp_serde;
-//extern crate rmpv;
-extern crate ws;
-extern crate hyper;
-extern crate tokio_core;
-extern crate tokio;
-extern crate tokio_io;
-extern crate futures;
-extern crate subprocess;
-extern crate regex;
-extern crate time;
-
-#[macro_use]
-extern crate serde_derive;
-
-#[macro_use]
-extern crate lazy_static;
+extern crate nodeeditor;
 
 #[macro_use]
 extern crate log;
 extern crate env_logger;
+extern crate ctrlc;
 
-macro_rules! profile {
-    ($name:expr, $bl:block) => ({
-        use log::Level::Trace;
-        if log_enabled!(Trace){
-            let name   = $name;
-            let start  = time::PreciseTime::now();
-            let result = $bl;
-            trace!("{} took {:?}", name, start.to(time::PreciseTime::now()));
-            result
-        }
-        else{
-            $bl
-        }
-    })
-}
-
-mod rebuilder;
-mod graph;
-mod websocket;
-mod http;
-mod file;
-mod filecache;
-mod filethread;
-mod reloader;
+use nodeeditor::{Builder, Config, ConfigError};
 
-use rebuilder::InvalidationReceiverChain;
-
-#[cfg(test)]
-mod tests{
-    extern crate test;
-    use super::*;
-    use self::test::Bencher;
-    use std::sync::{Arc, atomic::{AtomicBool, Ordering}};
-    
-    #[bench]
-    fn bench_roundrobin(b: &mut Bencher){
-        let rr = Arc::new(filethread::RoundRobin::new(8));
-        
-        b.iter(|| rr.get_next() );
-    }
-
-    #[bench]
-    fn bench_crowded_roundrobin(b: &mut Bencher){
-        let rr = Arc::new(filethread::RoundRobin::new(8));
-        let running =  Arc::new(AtomicBool::new(true));
-        let threads = (0..32).map(|_|{
-            let r = rr.clone();
-            let running = running.clone();
-            std::thread::spawn(move ||
-                while running.load(Ordering::Relaxed) == true{
-                    r.get_next();
-                })
-        }).collect::<Vec<std::thread::JoinHandle<_>>>();
-
-        b.iter(|| rr.get_next() );
-
-        running.store(false, Ordering::Relaxed);
-        for t in threads{
-            t.join().unwrap();
-        }
-    } 
-
-}
+use std::env;
+use std::process;
 
 fn main(){
     // configure logger    
@@ -128,7 +46,7 @@ fn main(){
             // if the path root is the main package's name, strip it
             let module_path = record.module_path().unwrap_or("unknown");
             let write_path = {
-                static MAIN_NAME: &'static str = env!("CARGO_PKG_NAME");
+                static MAIN_NAME: &str = env!("CARGO_PKG_NAME");
                 let min_len = min(module_path.len(), MAIN_NAME.len());
                 let (style, path) =
                     if module_path[0..min_len] == MAIN_NAME[..]{
@@ -143,7 +61,7 @@ fn main(){
                         (style, path)
                     }
                     else{
-                        (buf.style(), &module_path[..])
+                        (buf.style(), module_path)
                     };
                 write!(buf, "{:<12}] ", style.value(path))
             };
@@ -155,17 +73,33 @@ fn main(){
         })
         .init();
 
-    // start threads
-    let (rebuilder, invalidation_rx) = rebuilder::launch_thread();
-    let (invalidation_chain, invalidation_rx) =
-        InvalidationReceiverChain::with_daisy(invalidation_rx);
-    let cache     = filecache::FileCache::new(4, invalidation_chain);
-    let http      = http::launch_thread(cache);
-    let websocket = websocket::launch_thread();    
-    let reloader  = reloader::launch_thread(invalidation_rx.into());
-    debug!("Threads launched, waiting for join");
-    reloader.join().unwrap();
-    websocket.join().unwrap();
-    http.join().unwrap();
-    rebuilder.join().unwrap();
+    let config = match Config::from_args(env::args().skip(1)){
+        Ok(config) => config,
+        Err(ConfigError::Help) => {
+            println!("{}", ConfigError::Help);
+            return;
+        },
+        Err(e) => {
+            eprintln!("{}\n\n{}", e, ConfigError::Help);
+            process::exit(2);
+        }
+    };
+    debug!("Using {:?}", config);
+
+    let server = match Builder::new().config(config).start(){
+        Ok(server) => server,
+        Err(e) => {
+            error!("Couldn't start the server: {}", e);
+            process::exit(1);
+        }
+    };
+    // SIGINT and SIGTERM both shut down cleanly, so main returns and the process exits 0
+    let shutdown = server.shutdown_handle();
+    ctrlc::set_handler(move || shutdown.shutdown())
+        .expect("Couldn't set the SIGINT/SIGTERM handler");
+    debug!("Waiting for join");
+    if let Err(e) = server.join(){
+        error!("{}", e);
+        process::exit(1);
+    }
 }
@@ -0,0 +1,105 @@
+use hyper::header::ContentType;
+use hyper::mime::Mime;
+
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::path::Path;
+
+static DEFAULT_TYPES: &[(&str, &str)] = &[
+    ("html",   "text/html"),
+    ("htm",    "text/html"),
+    ("css",    "text/css"),
+    ("js",     "application/javascript"),
+    ("mjs",    "application/javascript"),
+    ("json",   "application/json"),
+    ("map",    "application/json"),
+    ("coffee", "text/coffeescript"),
+    ("txt",    "text/plain"),
+    ("md",     "text/markdown"),
+    ("xml",    "application/xml"),
+    ("svg",    "image/svg+xml"),
+    ("png",    "image/png"),
+    ("jpg",    "image/jpeg"),
+    ("jpeg",   "image/jpeg"),
+    ("gif",    "image/gif"),
+    ("ico",    "image/x-icon"),
+    ("webp",   "image/webp"),
+    ("woff",   "font/woff"),
+    ("woff2",  "font/woff2"),
+    ("ttf",    "font/ttf"),
+    ("wasm",   "application/wasm")
+];
+
+// textual types that don't start with text/ but should still say how they're encoded, and are
+// worth compressing
+static TEXT_TYPES: &[&str] = &[
+    "application/javascript",
+    "application/json",
+    "application/xml",
+    "image/svg+xml"
+];
+
+/// Maps file extensions to the Content-Type they're served with.
+pub struct MimeTypes{
+    types:        HashMap<String, Mime>,
+    compressible: HashSet<String>
+}
+
+impl MimeTypes{
+    /// The default mapping with `overrides` from extension to MIME type applied on top. Overrides
+    /// that don't parse are logged and skipped.
+    pub fn new(overrides: &BTreeMap<String, String>) -> MimeTypes{
+        let defaults = DEFAULT_TYPES.iter().map(|&(ext, ty)| (ext, ty));
+        let overrides = overrides.iter().map(|(ext, ty)| (&ext[..], &ty[..]));
+        let mut types        = HashMap::new();
+        let mut compressible = HashSet::new();
+        for (ext, ty) in defaults.chain(overrides){
+            let ext = ext.trim_start_matches('.').to_lowercase();
+            match with_charset(ty).parse(){
+                Ok(mime) => {
+                    if is_textual(ty){
+                        compressible.insert(ext.clone());
+                    }
+                    else{
+                        compressible.remove(&ext);
+                    }
+                    types.insert(ext, mime);
+                },
+                Err(_)   => warn!("Ignoring invalid MIME type {:?} for .{}", ty, ext)
+            }
+        }
+        MimeTypes{ types, compressible }
+    }
+
+    /// Whether files like `path` are text, which is worth compressing.
+    pub fn compressible(&self, path: &Path) -> bool{
+        match path.extension().and_then(|ext| ext.to_str()){
+            Some(ext) => self.compressible.contains(&ext.to_lowercase()),
+            None      => false
+        }
+    }
+
+    /// Unknown extensions are served as application/octet-stream.
+    pub fn content_type(&self, path: &Path) -> ContentType{
+        let mime = path.extension()
+            .and_then(|ext| ext.to_str())
+            .and_then(|ext| self.types.get(&ext.to_lowercase()));
+        match mime{
+            Some(mime) => ContentType(mime.clone()),
+            None       => ContentType::octet_stream()
+        }
+    }
+}
+
+fn is_textual(ty: &str) -> bool{
+    let essence = ty.split(';').next().unwrap_or("").trim();
+    essence.starts_with("text/") || TEXT_TYPES.contains(&essence)
+}
+
+fn with_charset(ty: &str) -> String{
+    if is_textual(ty) && !ty.contains("charset"){
+        format!("{}; charset=utf-8", ty)
+    }
+    else{
+        ty.into()
+    }
+}
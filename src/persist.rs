@@ -0,0 +1,266 @@
+use serde_json;
+
+use std::collections::BTreeMap;
+use std::cmp::{max, min};
+use std::ffi::OsStr;
+use std::fs;
+use std::io;
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, RwLock};
+use std::sync::mpsc::{channel, Sender, Receiver, RecvTimeoutError};
+use std::thread;
+use std::thread::{JoinHandle};
+use std::time::{Duration, Instant};
+
+use graph::{GraphId, GraphData};
+
+static NEXT_ID_FILE: &str = "next_id";
+static EPOCH_FILE:   &str = "epoch";
+
+// a steady stream of changes still gets written out at least this many debounce periods apart
+static MAX_DEBOUNCES: u32 = 10;
+
+enum Pending{
+    Graph(GraphId, Arc<RwLock<GraphData>>),
+    NextId(GraphId),
+    Stop
+}
+
+// Whichever thread changes a graph only hands it over, the persistence thread serializes and
+// writes out every changed graph once changes stop arriving for a while. It holds a read lock on
+// each graph while serializing it, so edits wait for that rather than every edit waiting for a
+// whole graph to be serialized.
+#[derive(Clone)]
+pub struct Persister{
+    tx: Arc<Mutex<Sender<Pending>>>
+}
+
+impl Persister{
+    pub fn launch(dir: PathBuf, debounce: Duration) -> (JoinHandle<()>, Persister){
+        let (tx, rx) = channel();
+        let handle = thread::Builder::new()
+            .name("persist".into())
+            .spawn(move ||{
+                PersistState::new(dir, debounce, rx).run()
+            }).unwrap();
+        (handle, Persister{ tx: Arc::new(Mutex::new(tx)) })
+    }
+
+    fn send(&self, pending: Pending){
+        if self.tx.lock().unwrap().send(pending).is_err(){
+            error!("Persistence thread has stopped, changes will be lost");
+        }
+    }
+
+    /// Marks graph `id` as changed, to be saved as it is when the changes are written out.
+    pub fn save(&self, id: GraphId, data: Arc<RwLock<GraphData>>){
+        self.send(Pending::Graph(id, data))
+    }
+
+    pub fn save_next_id(&self, next_id: GraphId){
+        self.send(Pending::NextId(next_id))
+    }
+
+    /// Flushes pending changes and stops the persistence thread even if clones of this Persister
+    /// are still around. Changes saved after this are lost.
+    pub fn stop(&self){
+        self.send(Pending::Stop)
+    }
+}
+
+struct PersistState{
+    dir:      PathBuf,
+    debounce: Duration,
+    rx:       Receiver<Pending>,
+    graphs:   BTreeMap<GraphId, Arc<RwLock<GraphData>>>,
+    next_id:  Option<GraphId>
+}
+
+impl PersistState{
+    fn new(dir: PathBuf, debounce: Duration, rx: Receiver<Pending>) -> PersistState{
+        PersistState{
+            dir,
+            debounce,
+            rx,
+            graphs:  BTreeMap::new(),
+            next_id: None
+        }
+    }
+
+    // returns false once asked to stop
+    fn add(&mut self, pending: Pending) -> bool{
+        match pending{
+            // only the latest version of each graph matters
+            Pending::Graph(id, data)  => { self.graphs.insert(id, data); },
+            Pending::NextId(next_id)  => { self.next_id = Some(next_id); },
+            Pending::Stop             => return false
+        }
+        true
+    }
+
+    fn flush(&mut self){
+        for (id, data) in ::std::mem::take(&mut self.graphs){
+            let s = match serde_json::to_string(&*data.read().unwrap()){
+                Ok(s)  => s,
+                Err(e) => {
+                    error!("Failed to serialize graph {}: {}", id, e);
+                    continue;
+                }
+            };
+            let path = graph_path(&self.dir, id);
+            match write_atomic(&path, s.as_bytes()){
+                Ok(_)  => trace!("Saved graph {} to {:?}", id, path),
+                Err(e) => error!("Failed to save graph {} to {:?}: {:?}", id, path, e)
+            }
+        }
+        if let Some(next_id) = self.next_id.take(){
+            let path = self.dir.join(NEXT_ID_FILE);
+            if let Err(e) = write_atomic(&path, format!("{}", next_id).as_bytes()){
+                error!("Failed to save next GraphId to {:?}: {:?}", path, e);
+            }
+        }
+    }
+
+    pub fn run(mut self){
+        // wait for a change, then keep collecting changes until they stop arriving
+        while let Ok(first) = self.rx.recv(){
+            let mut running = self.add(first);
+            let deadline = Instant::now() + self.debounce * MAX_DEBOUNCES;
+            while running{
+                let now = Instant::now();
+                if now >= deadline{
+                    break;
+                }
+                match self.rx.recv_timeout(min(self.debounce, deadline - now)){
+                    Ok(pending) => running = self.add(pending),
+                    Err(RecvTimeoutError::Timeout)      => break,
+                    Err(RecvTimeoutError::Disconnected) => break
+                }
+            }
+            self.flush();
+            if !running{
+                debug!("Asked to stop, stopping");
+                return;
+            }
+        }
+        debug!("All persisters dropped, stopping");
+    }
+}
+
+fn graph_path(dir: &Path, id: GraphId) -> PathBuf{
+    dir.join(format!("{}.json", id))
+}
+
+// write to a temporary file and rename it over the target so a crash never leaves half a file
+fn write_atomic(path: &Path, contents: &[u8]) -> io::Result<()>{
+    let tmp = path.with_extension("tmp");
+    {
+        let mut file = fs::File::create(&tmp)?;
+        file.write_all(contents)?;
+        file.sync_all()?;
+    }
+    fs::rename(&tmp, path)
+}
+
+/// Loads every graph saved in `dir` along with the next GraphId to hand out, creating `dir` if
+/// it doesn't exist yet. Files that can't be read or parsed are logged and skipped, only failing
+/// to create or list `dir` is an error.
+pub fn load(dir: &Path) -> io::Result<(BTreeMap<GraphId, GraphData>, GraphId)>{
+    fs::create_dir_all(dir)?;
+
+    let mut graphs = BTreeMap::new();
+    for entry in fs::read_dir(dir)?{
+        let path = match entry{
+            Ok(entry) => entry.path(),
+            Err(e)    => {
+                error!("Failed to read an entry of {:?}: {}", dir, e);
+                continue;
+            }
+        };
+        if path.extension() != Some(OsStr::new("json")){
+            continue;
+        }
+        let id = match path.file_stem()
+                           .and_then(|s| s.to_str())
+                           .and_then(|s| s.parse::<GraphId>().ok()){
+            Some(id) => id,
+            None     => {
+                warn!("Ignoring {:?}, not named after a GraphId", path);
+                continue;
+            }
+        };
+        let file = match fs::File::open(&path){
+            Ok(file) => file,
+            Err(e)   => {
+                error!("Failed to open {:?}: {}", path, e);
+                continue;
+            }
+        };
+        match serde_json::from_reader(io::BufReader::new(file)){
+            Ok(data) => { graphs.insert(id, data); },
+            Err(e)   => error!("Failed to load graph from {:?}: {}", path, e)
+        }
+    }
+
+    let next_id_path = dir.join(NEXT_ID_FILE);
+    let mut s = String::new();
+    let saved_next_id = match fs::File::open(&next_id_path).and_then(|mut f| f.read_to_string(&mut s)){
+        Ok(_) => s.trim().parse::<GraphId>().unwrap_or(0),
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => 0,
+        Err(e) => {
+            error!("Failed to read {:?}: {}", next_id_path, e);
+            0
+        }
+    };
+    // never hand out an id that's already taken, even if next_id is stale
+    let next_id = max(saved_next_id,
+                      graphs.keys().next_back().map(|id| id + 1).unwrap_or(0));
+
+    Ok((graphs, next_id))
+}
+
+/// Counts the times graphs have been loaded from `dir`, returning the new count. It's written out
+/// before returning so that two runs never get the same epoch, which is why failing to read or
+/// save it is an error.
+pub fn next_epoch(dir: &Path) -> io::Result<u32>{
+    let path = dir.join(EPOCH_FILE);
+    let mut s = String::new();
+    let epoch = match fs::File::open(&path).and_then(|mut f| f.read_to_string(&mut s)){
+        Ok(_) => s.trim().parse::<u32>().map_err(|e|
+            io::Error::new(io::ErrorKind::InvalidData, format!("Invalid epoch in {:?}: {}", path, e)))?,
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => 0,
+        Err(e) => return Err(e)
+    };
+    let next = epoch.checked_add(1).ok_or_else(||
+        io::Error::new(io::ErrorKind::InvalidData, format!("Epoch in {:?} can't go any higher", path)))?;
+    write_atomic(&path, format!("{}", next).as_bytes())?;
+    Ok(next)
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+    use graph::DataValue;
+    use std::env;
+    use std::process;
+
+    #[test]
+    fn graphs_are_saved_as_they_are_when_written_out(){
+        let dir = env::temp_dir().join(format!("nodeeditor-persist-{}", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let (handle, persister) = Persister::launch(dir.clone(), Duration::from_secs(60));
+        let data = Arc::new(RwLock::new(GraphData::default()));
+        persister.save(3, data.clone());
+        persister.save_next_id(4);
+        // changed after being marked but before the debounce is up, which stopping cuts short
+        data.write().unwrap().data.insert("x".into(), DataValue::from(1));
+        persister.stop();
+        handle.join().unwrap();
+
+        let (graphs, next_id) = load(&dir).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(next_id, 4);
+        assert_eq!(graphs.get(&3), Some(&*data.read().unwrap()));
+    }
+}
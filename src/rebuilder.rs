@@ -6,7 +6,7 @@ use futures::sync::mpsc::{channel as bounded_channel,
                           Receiver as BoundedReceiver,
                           SendError as BoundedSendError};
 use futures::{future, future::Either, Future, Stream, Sink, Poll, Async};
-use std::sync::mpsc::{channel as std_channel};
+use std::sync::mpsc::{channel as std_channel, RecvTimeoutError};
 use std::time::Duration;
 use std::io;
 use std::fs;
@@ -14,6 +14,7 @@ use std::path::{Path, PathBuf};
 use std::thread;
 use std::thread::{JoinHandle};
 use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
 
 pub type InvalidationPath     = Arc<PathBuf>;
 
@@ -57,7 +58,7 @@ impl<T> RepeatAfter<T>{
     }
     pub fn repeat(self) -> impl Future<Item=Option<BoundedSender<T>>, Error=BoundedSendError<T>>{
         if let Some(tx) = self.1{
-            Either::A(tx.send(self.0).map(|s| Some(s)))
+            Either::A(tx.send(self.0).map(Some))
         }
         else{
             Either::B(future::ok(None))
@@ -65,7 +66,7 @@ impl<T> RepeatAfter<T>{
     }
     pub fn repeat_block(self) -> Result<Option<BoundedSender<T>>, BoundedSendError<T>>{
         if let Some(tx) = self.1{
-            tx.send(self.0).wait().map(|s| Some(s))
+            tx.send(self.0).wait().map(Some)
         }
         else{
             Ok(None)
@@ -147,9 +148,9 @@ impl InvalidationReceiverChain{
     }
 }
 
-impl Into<InvalidationReceiverChain> for InvalidationReceiver{
-    fn into(self) -> InvalidationReceiverChain{
-        InvalidationReceiverChain::without_daisy(self)
+impl From<InvalidationReceiver> for InvalidationReceiverChain{
+    fn from(receiver: InvalidationReceiver) -> InvalidationReceiverChain{
+        InvalidationReceiverChain::without_daisy(receiver)
     }
 }
 
@@ -161,12 +162,12 @@ fn process_coffee(path: &Path) -> io::Result<()>{
         .arg(path)
         .join(){
             Ok(k) => k,
-            Err(e) => return Err(io::Error::new(io::ErrorKind::Other, format!("{:?}", e)))
+            Err(e) => return Err(io::Error::other(format!("{:?}", e)))
     };
 
     if exit_status != ExitStatus::Exited(0){
         error!("Error, returned {:?}", exit_status);
-        Err(io::Error::new(io::ErrorKind::Other, format!("exited with status {:?}", exit_status)))
+        Err(io::Error::other(format!("exited with status {:?}", exit_status)))
     }
     else{
         info!("{} processed.", path.to_str().unwrap());
@@ -188,14 +189,15 @@ fn check<P: AsRef<Path>>(path: P){
 
 
 fn recursive_find(path: &Path) -> io::Result<()>{
-    trace!("Entering {}", path.to_str().unwrap());
+    trace!("Entering {:?}", path);
     for p in fs::read_dir(path)?{
-        let e = p.unwrap();
+        let e = p?;
         trace!("Found file {:?}", e.file_name());
-        if e.file_type().unwrap().is_dir(){
+        let file_type = e.file_type()?;
+        if file_type.is_dir(){
             recursive_find(&e.path())?;
         }
-        if e.file_type().unwrap().is_file(){
+        if file_type.is_file(){
             let path = e.path();
             check(path)
         }
@@ -208,7 +210,7 @@ fn handle_event(event: DebouncedEvent, invalidation_tx: &mut InvalidationSender)
     use self::InvalidationEvent::*;
 
     let broadcast = move |s| invalidation_tx.clone().send(s).wait().unwrap();
-    fn to_str<'a>(p: &'a PathBuf) -> &'a str{
+    fn to_str(p: &Path) -> &str{
         p.to_str().unwrap_or("<nonunicode>")
     }
     // XXX maybe do check after broadcast?
@@ -236,30 +238,59 @@ fn handle_event(event: DebouncedEvent, invalidation_tx: &mut InvalidationSender)
     }
 }
 
-pub fn launch_thread() -> (JoinHandle<()>, InvalidationReceiver){
+/// Compiles the .coffee files under `watch_path` and recompiles them once changes have settled
+/// for `debounce`. Fails if `watch_path` can't be watched, e.g. because it doesn't exist. Setting
+/// the returned flag stops watching within `debounce`, after which the invalidation chain ends
+/// once it's drained.
+pub fn launch_thread(watch_path: PathBuf, debounce: Duration)
+    -> io::Result<(JoinHandle<()>, InvalidationReceiver, Arc<AtomicBool>)>
+{
     // must be Arc<Mutex<Bus>> so that InvalidationReceiverMaker can add more receivers
     let (mut invalidation_tx, invalidation_rx) = bounded_channel(INVALIDATION_CHANNEL_SIZE);
+    let stop = Arc::new(AtomicBool::new(false));
+    let stopped = stop.clone();
+    let (watching_tx, watching_rx) = std_channel();
     
     let handle = thread::Builder::new()
         .name("rebuilder".into())
         .spawn(move ||{
-        let watch_path = "client/";
-        trace!("Finding and processing existing .coffee files");
-        recursive_find(Path::new(watch_path)).unwrap();
-
         let (watcher_tx, watcher_rx) = std_channel();
 
-        let mut watcher = watcher(watcher_tx, Duration::from_millis(200)).unwrap();
+        // watching first so that nothing changed while compiling is missed
+        let watching = watcher(watcher_tx, debounce)
+            .and_then(|mut watcher|
+                watcher.watch(&watch_path, RecursiveMode::Recursive).map(|_| watcher));
+        let watcher = match watching{
+            Ok(watcher) => watcher,
+            Err(e)      => {
+                let _ = watching_tx.send(Err(io::Error::other(
+                    format!("Couldn't watch {:?}: {:?}", watch_path, e))));
+                return;
+            }
+        };
+        let _ = watching_tx.send(Ok(()));
 
-        watcher.watch(watch_path, RecursiveMode::Recursive).unwrap();
+        trace!("Finding and processing existing .coffee files");
+        if let Err(e) = recursive_find(&watch_path){
+            error!("Failed to process the files in {:?}: {:?}", watch_path, e);
+        }
 
-        loop{
-            match watcher_rx.recv(){
+        while !stopped.load(Ordering::SeqCst){
+            match watcher_rx.recv_timeout(debounce){
                 Ok(ev) => handle_event(ev, &mut invalidation_tx),
-                Err(e) => error!("watch error: {:?}", e)
+                Err(RecvTimeoutError::Timeout) => {},
+                Err(e) => {
+                    error!("watch error: {:?}", e);
+                    break;
+                }
             }
         }
-    }).unwrap();
+        // dropping the watcher and invalidation_tx lets the rest of the chain finish
+        drop(watcher);
+        info!("Stopped watching {:?}", watch_path);
+    })?;
+    watching_rx.recv()
+        .unwrap_or_else(|_| Err(io::Error::other("the rebuilder stopped before watching")))?;
 
-    (handle, invalidation_rx)
+    Ok((handle, invalidation_rx, stop))
 }
@@ -1,25 +1,28 @@
-use ws::{Handler, Factory, Sender, Handshake, Request, Response as WsResponse, CloseCode, WebSocket};
+use ws::{Handler, Factory, Sender, Handshake, Request, Response as WsResponse, CloseCode};
 use ws::{Result as WsResult};
-use futures::Stream;
+use futures::{Future, Stream};
 
+use std::io;
 use std::thread;
 use std::thread::{JoinHandle};
+use std::net::SocketAddr;
+use std::sync::mpsc::channel;
 
 use rebuilder::InvalidationReceiverChain;
+use websocket::{Connections, OpenConnection, bind, bound, client_addr};
 use log::Level;
 
 // does nothing but keep the connection open and keep address if trace is on
-#[derive(Default)]
 struct NullHandler{
-    addr: Option<String>
+    addr:  Option<String>,
+    _open: OpenConnection
 }
-struct ServerFactory; // builds NullHandlers
+struct ServerFactory(Connections); // builds NullHandlers
 
 impl Handler for NullHandler{
     fn on_open(&mut self, hs: Handshake) -> WsResult<()>{
         if log_enabled!(Level::Trace){ // don't bother populating addr if not Tracing
-            if let Some(ip_addr) = hs.peer_addr {
-                let ip_string = format!("{}", ip_addr);
+            if let Some(ip_string) = client_addr(&hs){
                 info!("{:>20} - connection established",
                       ip_string);
                 self.addr = Some(ip_string);
@@ -46,34 +49,48 @@ impl Factory for ServerFactory{
     type Handler = NullHandler;
 
     fn connection_made(&mut self, _out: Sender) -> Self::Handler{
-        NullHandler::default()
+        NullHandler{ addr: None, _open: self.0.open() }
     }
 }
 
-pub fn launch_thread(invalidation_rx: InvalidationReceiverChain)
-    -> JoinHandle<()>
+/// Tells every client listening on `listen_addr` to reload when the client's files change.
+/// Calling shutdown on the returned Sender stops the server. Also returns the address actually
+/// bound, which differs from `listen_addr` if its port is 0. Open connections are counted in
+/// `connections`.
+pub fn launch_thread(listen_addr: SocketAddr, invalidation_rx: InvalidationReceiverChain,
+                     connections: Connections)
+    -> io::Result<(JoinHandle<()>, Sender, SocketAddr)>
 {
-    thread::Builder::new()
+    let (bound_tx, bound_rx) = channel();
+    let handle = thread::Builder::new()
         .name("reloader".into())
         .spawn(move || {
-            let factory = ServerFactory;
-            let listen_addr = "127.0.0.1:3002";
-            info!("Attempting to listen on {}", listen_addr);
-            let server = WebSocket::new(factory).unwrap();
+            let factory = ServerFactory(connections);
+            let (server, addr) = match bind(factory, listen_addr){
+                Ok(bound) => bound,
+                Err(e)    => {
+                    let _ = bound_tx.send(Err(e));
+                    return;
+                }
+            };
             let broadcaster = server.broadcaster();
+            let _ = bound_tx.send(Ok((broadcaster.clone(), addr)));
+            info!("Listening on {}", addr);
             // lazily spawn another thread to handle the mpsc events
             let handle = thread::Builder::new()
                 .name("reload bcast".into())
                 .spawn(move || {
                     invalidation_rx.recv()
-                        .map(move |e| {
+                        .for_each(move |e| {
                             use rebuilder::{InvalidationPath, InvalidationEvent::*};
                             let e = e.unwrap();
                             let check = |p: InvalidationPath|
                                 if p.ends_with("main.js") ||
                                    p.ends_with("index.html") ||
                                    p.ends_with("index.css"){
-                                    broadcaster.send("Reload").unwrap();
+                                    if let Err(e) = broadcaster.send("Reload"){
+                                        debug!("Couldn't broadcast reload: {:?}", e);
+                                    }
                                 };
                             match e{
                                 Added(p)       |
@@ -81,11 +98,19 @@ pub fn launch_thread(invalidation_rx: InvalidationReceiverChain)
                                 Renamed(.., d) => check(d),
                                 _ => {}
                             }
-                        }).wait().last().unwrap()
+                            Ok(())
+                        }).wait()
+                          .unwrap_or_else(|_| error!("Invalidations failed"));
+                    debug!("Invalidations stopped, no more reloads");
                 }).unwrap();
-            server.listen(listen_addr).unwrap();
-            let join = handle.join();
-            panic!("reloader broadcast thread joined: {:?}", join)
-        }).unwrap()
+            server.run().unwrap();
+            // the broadcast thread stops once the rebuilder does and the invalidations are drained
+            if handle.join().is_err(){
+                error!("The reload broadcast thread panicked");
+            }
+            info!("Stopped listening on {}", addr);
+        })?;
+    let (broadcaster, addr) = bound(bound_rx)?;
+    Ok((handle, broadcaster, addr))
 }
 
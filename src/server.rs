@@ -0,0 +1,384 @@
+use futures::sync::oneshot;
+use ws::{CloseCode, Sender as WsSender};
+
+use std::io;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread::{JoinHandle};
+use std::time::Duration;
+
+use config::Config;
+use eval::Evaluator;
+use file::FileOptions;
+use graph::GraphStore;
+use persist;
+use persist::Persister;
+use filecache::{FileCache, CacheLimits, CacheStats, SharedStats};
+use rebuilder;
+use rebuilder::InvalidationReceiverChain;
+use websocket;
+use websocket::Connections;
+use http;
+use reloader;
+
+/// Path on the HTTP port that reaches the graph websocket.
+pub static GRAPH_PATH:  &str = "/ws/graph";
+/// Path on the HTTP port that reaches the reload websocket.
+pub static RELOAD_PATH: &str = "/ws/reload";
+
+// how long websocket clients get to acknowledge the close before they're dropped
+const CLOSE_TIMEOUT_MS: u64 = 2000;
+// how long saved graphs wait for further changes before they're written out
+const PERSIST_DEBOUNCE_MS: u64 = 500;
+
+type StoreHook = Box<dyn FnMut(&GraphStore) + Send>;
+
+/// Configures the HTTP, websocket and reloader services, which all run on their own threads.
+/// Ports can be 0 to have the OS pick one, the Server reports the addresses actually bound.
+pub struct Builder{
+    config:    Config,
+    evaluator: Option<Evaluator>,
+    on_store:  Option<StoreHook>
+}
+
+impl Default for Builder{
+    fn default() -> Builder{
+        Builder{
+            // an embedded server only saves and evaluates graphs if asked to
+            config:    Config{ graph_dir: None, evaluate: false, ..Config::default() },
+            evaluator: None,
+            on_store:  None
+        }
+    }
+}
+
+impl Builder{
+    pub fn new() -> Builder{
+        Builder::default()
+    }
+
+    /// Replaces every setting with those in `config`.
+    pub fn config(mut self, config: Config) -> Builder{
+        self.config = config;
+        self
+    }
+    pub fn http_addr(mut self, addr: SocketAddr) -> Builder{
+        self.config.http_addr = addr;
+        self
+    }
+    pub fn ws_addr(mut self, addr: SocketAddr) -> Builder{
+        self.config.ws_addr = addr;
+        self
+    }
+    pub fn reload_addr(mut self, addr: SocketAddr) -> Builder{
+        self.config.reload_addr = addr;
+        self
+    }
+    /// Directory the client is served from and whose .coffee files are compiled.
+    pub fn root<P: Into<PathBuf>>(mut self, root: P) -> Builder{
+        self.config.root = root.into();
+        self
+    }
+    /// Saves graphs to `dir` and restores them from it on start. Graphs are only kept in memory
+    /// without one.
+    pub fn graph_dir<P: Into<PathBuf>>(mut self, dir: P) -> Builder{
+        self.config.graph_dir = Some(dir.into());
+        self
+    }
+    pub fn file_threads(mut self, n_threads: usize) -> Builder{
+        self.config.file_threads = n_threads;
+        self
+    }
+    /// Most bytes of file contents the cache keeps, the least recently used files are dropped
+    /// beyond that.
+    pub fn cache_bytes(mut self, max_bytes: usize) -> Builder{
+        self.config.cache_bytes = max_bytes;
+        self
+    }
+    /// Most files the cache keeps, counting each encoding of a file separately.
+    pub fn cache_entries(mut self, max_entries: usize) -> Builder{
+        self.config.cache_entries = max_entries;
+        self
+    }
+    /// Files bigger than `max_file_size` bytes are streamed from disk for every request instead of
+    /// cached, and sent uncompressed.
+    pub fn cache_max_file(mut self, max_file_size: usize) -> Builder{
+        self.config.cache_max_file = max_file_size;
+        self
+    }
+    /// Serves files ending in `.ext` with the Content-Type `mime_type`.
+    pub fn mime_type<E: Into<String>, T: Into<String>>(mut self, ext: E, mime_type: T) -> Builder{
+        self.config.mime_types.insert(ext.into(), mime_type.into());
+        self
+    }
+    /// File served for requests to a directory, index.html by default.
+    pub fn index<S: Into<String>>(mut self, index: S) -> Builder{
+        self.config.index = index.into();
+        self
+    }
+    /// Lists the contents of directories without an index file instead of answering 404.
+    pub fn listing(mut self, listing: bool) -> Builder{
+        self.config.listing = listing;
+        self
+    }
+    /// Serves `fallback`, relative to the root, for missing paths without an extension so client
+    /// side routes load the app.
+    pub fn fallback<P: Into<PathBuf>>(mut self, fallback: P) -> Builder{
+        self.config.fallback = Some(fallback.into());
+        self
+    }
+    /// Runs graphs with `Evaluator::with_builtins` after every edit if `evaluate` is set.
+    pub fn evaluate(mut self, evaluate: bool) -> Builder{
+        self.config.evaluate = evaluate;
+        self
+    }
+    /// Runs graphs with `evaluator` after every edit, whether or not `evaluate` is set.
+    pub fn evaluator(mut self, evaluator: Evaluator) -> Builder{
+        self.evaluator = Some(evaluator);
+        self
+    }
+    /// Calls `on_store` with the GraphStore before any client connects. `Server::store` gives
+    /// access to it afterwards.
+    pub fn on_store<F>(mut self, on_store: F) -> Builder
+        where F: FnMut(&GraphStore) + Send + 'static
+    {
+        let on_store: StoreHook = Box::new(on_store);
+        self.on_store = Some(on_store);
+        self
+    }
+
+    /// Launches every service, failing if `graph_dir` can't be read, `root` can't be watched or
+    /// any service can't bind its address. Services already launched are stopped again in that case.
+    pub fn start(self) -> io::Result<Server>{
+        let config = self.config;
+        // graphs are restored before anything can connect
+        let (mut store, persist) = match config.graph_dir{
+            Some(ref dir) => {
+                let (graphs, next_id) = persist::load(dir)?;
+                let epoch = persist::next_epoch(dir)?;
+                info!("Restored {} graphs from {:?}", graphs.len(), dir);
+                let (persist, persister) =
+                    Persister::launch(dir.clone(), Duration::from_millis(PERSIST_DEBOUNCE_MS));
+                (GraphStore::restore(persister.clone(), graphs, next_id, epoch), Some((persist, persister)))
+            },
+            None => (GraphStore::default(), None)
+        };
+        let evaluator = match self.evaluator{
+            Some(evaluator)           => Some(evaluator),
+            None if config.evaluate   => Some(Evaluator::with_builtins()),
+            None                      => None
+        };
+        if let Some(evaluator) = evaluator{
+            store.set_evaluator(evaluator);
+        }
+        if let Some(mut on_store) = self.on_store{
+            on_store(&store);
+        }
+        let (rebuilder, invalidation_rx, rebuilder_stop) =
+            rebuilder::launch_thread(config.root.clone(), config.watch_debounce())
+                .inspect_err(|_| if let Some((_, ref persister)) = persist{ persister.stop(); })?;
+        let (invalidation_chain, invalidation_rx) =
+            InvalidationReceiverChain::with_daisy(invalidation_rx);
+        let limits = CacheLimits{
+            max_bytes:     config.cache_bytes,
+            max_entries:   config.cache_entries,
+            max_file_size: config.cache_max_file
+        };
+        let cache = FileCache::new(config.file_threads, limits, invalidation_chain);
+        let cache_stats = cache.stats();
+        let ws_connections     = Connections::default();
+        let reload_connections = Connections::default();
+        let stop_early = |started: &[&WsSender]|{
+            for out in started{
+                let _ = out.shutdown();
+            }
+            rebuilder_stop.store(true, Ordering::SeqCst);
+            if let Some((_, ref persister)) = persist{
+                persister.stop();
+            }
+        };
+        let (websocket, websocket_out, ws_addr) =
+            websocket::launch_thread(config.ws_addr, store.clone(), ws_connections.clone())
+                .inspect_err(|_| stop_early(&[]))?;
+        let (reloader, reloader_out, reload_addr) =
+            reloader::launch_thread(config.reload_addr, invalidation_rx.into(),
+                                    reload_connections.clone())
+                .inspect_err(|_| stop_early(&[&websocket_out]))?;
+        // the websockets are also reachable through the HTTP port so a single origin is enough
+        let upgrades = vec![(GRAPH_PATH.into(),  ws_addr),
+                            (RELOAD_PATH.into(), reload_addr)];
+        let files = FileOptions{
+            root:       config.root,
+            mime_types: config.mime_types,
+            index:      config.index,
+            listing:    config.listing,
+            fallback:   config.fallback
+        };
+        let (http, http_shutdown, http_addr) =
+            http::launch_thread(config.http_addr, files, cache, store.clone(), upgrades)
+                .inspect_err(|_| stop_early(&[&websocket_out, &reloader_out]))?;
+        debug!("Threads launched");
+        Ok(Server{
+            store,
+            persist,
+            cache_stats,
+            rebuilder,
+            http,
+            http_addr,
+            websocket,
+            ws_addr,
+            reloader,
+            reload_addr,
+            shutdown: Shutdown{
+                started: Arc::new(AtomicBool::new(false)),
+                rebuilder_stop,
+                http_shutdown: Arc::new(Mutex::new(Some(http_shutdown))),
+                websocket_out,
+                ws_connections,
+                reloader_out,
+                reload_connections
+            }
+        })
+    }
+}
+
+/// Stops the services of a Server from any thread. Clones stop the same Server.
+#[derive(Clone)]
+pub struct Shutdown{
+    started:            Arc<AtomicBool>,
+    rebuilder_stop:     Arc<AtomicBool>,
+    http_shutdown:      Arc<Mutex<Option<oneshot::Sender<()>>>>,
+    websocket_out:      WsSender,
+    ws_connections:     Connections,
+    reloader_out:       WsSender,
+    reload_connections: Connections
+}
+
+impl Shutdown{
+    /// Closes every websocket connection with CloseCode::Away, waiting briefly for clients to
+    /// acknowledge, then stops the servers and the file watcher. Returns without waiting for the
+    /// threads to finish, `Server::join` does that. Only the first call does anything.
+    pub fn shutdown(&self){
+        if self.started.swap(true, Ordering::SeqCst){
+            return;
+        }
+        info!("Shutting down");
+        // websockets first, those reached through the HTTP port need it to pass on the close
+        close_all("graph", &self.websocket_out, &self.ws_connections);
+        close_all("reload", &self.reloader_out, &self.reload_connections);
+        if let Some(http_shutdown) = self.http_shutdown.lock().unwrap().take(){
+            let _ = http_shutdown.send(());
+        }
+        self.rebuilder_stop.store(true, Ordering::SeqCst);
+    }
+}
+
+fn close_all(name: &str, out: &WsSender, connections: &Connections){
+    if let Err(e) = out.close(CloseCode::Away){
+        error!("Failed to close {} connections: {:?}", name, e);
+    }
+    if !connections.wait_closed(Duration::from_millis(CLOSE_TIMEOUT_MS)){
+        warn!("Some {} connections didn't close in time", name);
+    }
+    if let Err(e) = out.shutdown(){
+        error!("Failed to shut down the {} websocket server: {:?}", name, e);
+    }
+}
+
+/// Handles to the services started by a Builder.
+pub struct Server{
+    store:       GraphStore,
+    persist:     Option<(JoinHandle<()>, Persister)>,
+    cache_stats: SharedStats,
+    rebuilder:   JoinHandle<()>,
+    http:        JoinHandle<()>,
+    http_addr:   SocketAddr,
+    websocket:   JoinHandle<()>,
+    ws_addr:     SocketAddr,
+    reloader:    JoinHandle<()>,
+    reload_addr: SocketAddr,
+    shutdown:    Shutdown
+}
+
+impl Server{
+    pub fn http_addr(&self) -> SocketAddr{
+        self.http_addr
+    }
+    pub fn ws_addr(&self) -> SocketAddr{
+        self.ws_addr
+    }
+    pub fn reload_addr(&self) -> SocketAddr{
+        self.reload_addr
+    }
+
+    /// The graphs served over the websocket. The store can be used from any thread, edits made
+    /// through it are saved and broadcast to the clients they're meant for.
+    pub fn store(&self) -> GraphStore{
+        self.store.clone()
+    }
+
+    /// Hits, misses and evictions of the cache files are served from, and how much it holds.
+    pub fn cache_stats(&self) -> CacheStats{
+        self.cache_stats.get()
+    }
+
+    /// A handle that stops this Server, for use from signal handlers or other threads.
+    pub fn shutdown_handle(&self) -> Shutdown{
+        self.shutdown.clone()
+    }
+
+    /// Blocks until every service has stopped, which is never unless something shuts them down.
+    /// Fails if any of them panicked, after waiting for the others.
+    pub fn join(self) -> io::Result<()>{
+        // dropping the last Shutdown would stop the HTTP server, so keep it until everything's joined
+        let Server{ persist, rebuilder, http, websocket, reloader, shutdown: _shutdown, .. } = self;
+        let mut panicked = Vec::new();
+        let mut join = |name: &'static str, handle: JoinHandle<()>|
+            if handle.join().is_err(){
+                error!("The {} thread panicked", name);
+                panicked.push(name);
+            };
+        join("websocket", websocket);
+        join("http", http);
+        // clients are gone by now, but others may still hold the store so ask the persister to stop
+        if let Some((persist, persister)) = persist{
+            persister.stop();
+            join("persist", persist);
+            debug!("Saved graphs flushed");
+        }
+        join("rebuilder", rebuilder);
+        join("reloader", reloader);
+        info!("Shut down");
+        if panicked.is_empty(){
+            Ok(())
+        }
+        else{
+            Err(io::Error::other(format!("threads panicked: {}", panicked.join(", "))))
+        }
+    }
+
+    /// Stops every service and waits for them to finish, saved graphs are flushed by then.
+    pub fn shutdown(self) -> io::Result<()>{
+        self.shutdown.shutdown();
+        self.join()
+    }
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+
+    #[test]
+    fn missing_root_is_an_error(){
+        let any_port: SocketAddr = "127.0.0.1:0".parse().unwrap();
+        let started = Builder::new()
+            .root("/nonexistent/client")
+            .http_addr(any_port)
+            .ws_addr(any_port)
+            .reload_addr(any_port)
+            .start();
+        assert!(started.is_err());
+    }
+}
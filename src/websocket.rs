@@ -1,5 +1,6 @@
 use serde_json;
-use ws::{listen, Handler, Factory, Sender, Handshake, Request, Response as WsResponse, Message, CloseCode};
+use rmp_serde;
+use ws::{Handler, Factory, Sender, Handshake, Request, Response as WsResponse, Message, CloseCode, WebSocket};
 use ws::{Error as WsError, ErrorKind as WsErrorKind, Result as WsResult};
 
 use graph::{PossibleErr as GraphErr, *};
@@ -7,14 +8,58 @@ use graph::{PossibleErr as GraphErr, *};
 use std::thread;
 use std::thread::{JoinHandle};
 use std::fmt;
+use std::io;
 use std::result;
+use std::time::{Duration, Instant};
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex, Condvar};
+use std::sync::mpsc::{channel, Receiver};
+
+/// Counts the open connections of a websocket server, so shutting down can wait for them to close.
+#[derive(Clone, Default)]
+pub struct Connections(Arc<(Mutex<usize>, Condvar)>);
+
+impl Connections{
+    pub fn open(&self) -> OpenConnection{
+        *(self.0).0.lock().unwrap() += 1;
+        OpenConnection(self.clone())
+    }
+
+    /// Waits up to `timeout` for every connection to close, returning false if some are still open.
+    pub fn wait_closed(&self, timeout: Duration) -> bool{
+        let (count, closed) = &*self.0;
+        let deadline  = Instant::now() + timeout;
+        let mut count = count.lock().unwrap();
+        while *count > 0{
+            let now = Instant::now();
+            if now >= deadline{
+                return false;
+            }
+            count = closed.wait_timeout(count, deadline - now).unwrap().0;
+        }
+        true
+    }
+}
+
+/// Held by a connection's handler, so the connection is counted until the handler is dropped.
+pub struct OpenConnection(Connections);
+
+impl Drop for OpenConnection{
+    fn drop(&mut self){
+        let (count, closed) = &*(self.0).0;
+        *count.lock().unwrap() -= 1;
+        closed.notify_all();
+    }
+}
+
 
 enum PossibleErr{
-    Ws(WsError),
+    Ws(Box<WsError>),
     String(String),
     GraphErr(GraphErr),
     JsonErr(serde_json::Error),
-    Disp(Box<fmt::Display>),
+    MsgPackErr(rmp_serde::decode::Error),
+    Disp(Box<dyn fmt::Display>),
     None
 }
 
@@ -26,6 +71,7 @@ impl fmt::Display for PossibleErr {
             String(ref s)   => s.fmt(f),
             GraphErr(ref g) => g.fmt(f),
             JsonErr(ref j)  => j.fmt(f),
+            MsgPackErr(ref m) => m.fmt(f),
             Disp(ref d)     => d.fmt(f),
             None            => (None).fmt(f)
         }
@@ -34,15 +80,9 @@ impl fmt::Display for PossibleErr {
 
 type Result<T> = result::Result<T, PossibleErr>;
 
-impl From<::std::option::NoneError> for PossibleErr{
-    fn from(_: ::std::option::NoneError) -> PossibleErr{
-        PossibleErr::None
-    }
-}
-
 impl From<WsError> for PossibleErr{
     fn from(g: WsError) -> PossibleErr{
-        PossibleErr::Ws(g)
+        PossibleErr::Ws(Box::new(g))
     }
 }
 
@@ -58,12 +98,18 @@ impl From<serde_json::Error> for PossibleErr{
     }
 }
 
+impl From<rmp_serde::decode::Error> for PossibleErr{
+    fn from(m: rmp_serde::decode::Error) -> PossibleErr{
+        PossibleErr::MsgPackErr(m)
+    }
+}
+
 
 fn to_ws_err(e: PossibleErr, kind: WsErrorKind) -> WsError{
     use self::GraphErr::Ws as GWs;
     use self::PossibleErr::*;
     match e{
-        Ws(w) | GraphErr(GWs(w)) => w,
+        Ws(w) | GraphErr(GWs(w)) => *w,
         _ => WsError::new(kind,
                           format!("{}", e))
     }
@@ -73,73 +119,97 @@ fn to_ws(e: PossibleErr) -> WsError{
     to_ws_err(e, WsErrorKind::Internal)
 }
 
-impl Into<WsError> for PossibleErr{
-    fn into(self) -> WsError{
-       to_ws(self)
+impl From<PossibleErr> for WsError{
+    fn from(e: PossibleErr) -> WsError{
+       to_ws(e)
     }
 }
 
-fn decode_command(msg: Message) -> Result<Command>{
-    match msg{
-        Message::Text(t) => {
+fn decode_command(codec: Codec, msg: Message) -> Result<Envelope>{
+    match (codec, msg){
+        // text is always JSON so that an attach can switch codecs
+        (_, Message::Text(t)) => {
             Ok(serde_json::from_str(&t[..])?)
         },
-        Message::Binary(..) =>
+        (Codec::MsgPack, Message::Binary(b)) => {
+            Ok(rmp_serde::from_slice(&b[..])?)
+        },
+        (Codec::Json, Message::Binary(..)) =>
             Err(WsError::new(
                 WsErrorKind::Protocol,
-                format!("binary message received where expecting text JSON")).into())
+                "binary message received but MessagePack wasn't negotiated").into())
     }
 }
 
-fn encode_response(response: Response) -> WsResult<Message>{
-    match serde_json::to_string(&response){
-        Ok(s)  => Ok(Message::Text(s)),
-        Err(e) => Err(WsError::new(WsErrorKind::Internal, format!("encode_response failed {:?}", e)))
-    }
+// these go straight back to ws, so they fail with its error type
+#[allow(clippy::result_large_err)]
+fn encode_response<T: Into<Reply>>(codec: Codec, reply: T) -> WsResult<Message>{
+    codec.encode(&Update::Response(reply.into()))
 }
 
-fn encode_update<T: Into<Update>>(update: T) -> WsResult<Message>{
-    match serde_json::to_string(&update.into()){
-        Ok(s)  => Ok(Message::Text(s)),
-        Err(e) => Err(WsError::new(WsErrorKind::Internal, format!("encode_update failed {:?}", e)))
-    }
+#[allow(clippy::result_large_err)]
+fn encode_update<T: Into<Update>>(codec: Codec, update: T) -> WsResult<Message>{
+    codec.encode(&update.into())
 }
 
 struct ClientCommon;
 impl ClientCommon{
     fn on_open(out: &Sender, store: &GraphStore, id: GraphId,
-               client_type: ClientType) -> Result<()>{
-        if let Ok(_) = store.attach(id, client_type, out.clone()){
+               client_type: ClientType, codec: Codec) -> Result<()>{
+        if store.attach(id, client_type, codec, out.clone()).is_ok(){
             trace!("Client supplied valid GraphId {}", id);
             Ok(())
         }
         else{
             let err = format!("GraphId {} does not exist", id);
-            out.send(encode_response(Response::Error(DataValue::from(err.clone())))?)?;
+            out.send(encode_response(codec, Response::Error(DataValue::from(err.clone())))?)?;
             Ok(()) //Err(WsError::new(WsErrorKind::Protocol, err))
         }
     }
     fn on_command(_out: &Sender, store: &GraphStore,
                   command: &Command, graph: GraphId,
-                  _client_type: ClientType) -> Result<Option<Response>> {
+                  client_type: ClientType) -> Result<Option<Response>> {
         use graph::Command::*; 
+        let id    = graph;
+        let graph = match *command{
+            AddLink{..}    | DelLink{..}    |
+            AddNode{..}    | RemoveNode{..} |
+            MoveNode{..}   | ReplaceNode{..} |
+            LinkTargets{..} | FindCycle      |
+            Undo | Redo => store.get(graph)?,
+            _ => {return Ok(None)}
+        };
         let response = match *command{
-            AddLink{ source: ref from, target: ref to } => {
-                let graph = store.get(graph)?;
-                
-                let result = graph.add_link(from, to);
-                match result{
-                    Response::Ok => {
-                        graph.repeat_to(ClientType::Both, encode_update(command.clone())?);
-                        Response::Ok
-                    }
-                    _ => result
+            // queries answer with their own Response so they are never repeated
+            LinkTargets{ ref source } => graph.link_targets(source),
+            FindCycle => graph.find_cycle(),
+            Undo | Redo if client_type != ClientType::Frontend =>
+                Response::Error(DataValue::from(format!("Only frontends can {:?}", command))),
+            Undo => Self::after_replay(store, id, graph.undo())?,
+            Redo => Self::after_replay(store, id, graph.redo())?,
+            _ => {
+                // only a frontend's own edits are recorded, so that's all undo reverts
+                let response = match client_type{
+                    ClientType::Frontend => store.edit(id, command)?,
+                    _                    => store.apply(id, command, ClientType::Both)?
+                };
+                if response == Response::Ok{
+                    store.evaluate(id, None)?;
                 }
-            },
-            _ => {return Ok(None)}
+                response
+            }
         };
         Ok(Some(response))
     }
+
+    // an undo or redo has already broadcast the commands it applied
+    fn after_replay(store: &GraphStore, id: GraphId, response: Response) -> Result<Response>{
+        if response == Response::Ok{
+            store.changed(id);
+            store.evaluate(id, None)?;
+        }
+        Ok(response)
+    }
 }
 
 #[derive(Copy,Clone)]
@@ -148,21 +218,34 @@ struct FrontendClient{
 }
 
 impl FrontendClient{
-    fn on_open(out: &Sender, store: &GraphStore, id: GraphId) -> Result<Self>{
-        ClientCommon::on_open(out, store, id, ClientType::Frontend)?;
+    fn on_open(out: &Sender, store: &GraphStore, id: GraphId, codec: Codec,
+               since: Option<Seq>) -> Result<Self>{
+        let graph  = store.get(id)?;
+        // nothing can be broadcast between attaching and catching up while the log is held
+        let log    = graph.log.lock().unwrap();
+        ClientCommon::on_open(out, store, id, ClientType::Frontend, codec)?;
         trace!("Frontend attached to GraphId {}", id);
-        out.send(
-            encode_update(
-                Command::SetGraph{
-                    graph: store.get(id)?.data.clone()
+        let missed = since.and_then(|last| log.since(&ClientType::Frontend, last));
+        match missed{
+            Some(updates) => {
+                trace!("Replaying {} missed updates since {:?}", updates.len(), since);
+                for update in updates{
+                    out.send(codec.encode(&update)?)?;
                 }
-            )?
-        )?;
-        out.send(
-            encode_update(
-                Response::Warning("Test Warning".into())
-            )?
-        )?;
+            },
+            // never attached before, or the log no longer reaches back far enough
+            None => {
+                let seq = log.last();
+                out.send(
+                    codec.encode(&Sequenced{
+                        seq,
+                        update: Command::SetGraph{
+                            graph: Arc::new(graph.data.read().unwrap().clone())
+                        }.into()
+                    })?
+                )?;
+            }
+        }
         Ok(FrontendClient{ graph: id })
     }
 
@@ -173,12 +256,9 @@ impl FrontendClient{
         if let Some(common) = ClientCommon::on_command(out, store, command, self.graph, ClientType::Frontend)?{
             return Ok(common);
         }
-        match *command{
-            _ => Err(WsError::new(
-                        WsErrorKind::Protocol,
-                        format!("Expected Frontend command, got {:?}",
-                                command)).into())
-        }
+        Err(WsError::new(
+                WsErrorKind::Protocol,
+                format!("Expected Frontend command, got {:?}", command)).into())
     }
 }
 
@@ -188,8 +268,8 @@ struct BackendClient{
 }
 
 impl BackendClient{
-    fn on_open(out: &Sender, store: &GraphStore, id: GraphId) -> Result<Self>{
-        ClientCommon::on_open(out, store, id, ClientType::Backend)?;
+    fn on_open(out: &Sender, store: &GraphStore, id: GraphId, codec: Codec) -> Result<Self>{
+        ClientCommon::on_open(out, store, id, ClientType::Backend, codec)?;
         trace!("Backend attached to GraphId {}", id);
         Ok(BackendClient{ graph: id })
     } 
@@ -199,23 +279,30 @@ impl BackendClient{
         use graph::Command::*;
         let client_type = ClientType::Backend;
        
-        if let Some(common) = ClientCommon::on_command(out, store, command, self.graph, client_type.clone())?{
+        if let Some(common) = ClientCommon::on_command(out, store, command, self.graph, client_type)?{
             return Ok(common);
         }
         
         match *command{
             SetGraph{ ref graph } => Ok({
                 trace!("set graph {:?}", graph);
-                store.set_graph(self.graph, graph.clone())?;
-                store.repeat_to(self.graph, client_type.opposite(),
-                                encode_update(command.clone())?)?;
-                Response::Ok
+                let errors = graph.check_links();
+                if !errors.is_empty(){
+                    Response::Error(DataValue::List(
+                        errors.into_iter().map(DataValue::from).collect()))
+                }
+                else{
+                    store.set_graph(self.graph, graph.clone(), client_type.opposite())?;
+                    store.evaluate(self.graph, None)?;
+                    Response::Ok
+                }
             }),
             SetData{ ref id, ref value } => Ok({
                 trace!("set data {:?} = {:?}", id, value);
-                //store.set_data(self.graph, id, value)?;
-                store.repeat_to(self.graph, client_type.opposite(),
-                                encode_update(command.clone())?)?;
+                if store.set_data(self.graph, id, value, client_type.opposite())?{
+                    trace!("{:?} is a new DataId", id);
+                }
+                store.evaluate(self.graph, Some(id))?;
                 Response::Ok
             }),
             _ => Err(WsError::new(WsErrorKind::Protocol,
@@ -236,13 +323,15 @@ struct ServerHandler{
     out:   Sender,
     store: GraphStore,
     state: ClientState,
-    addr:  String
+    codec: Codec,
+    acks:  bool,
+    addr:  String,
+    _open: OpenConnection
 }
 
 impl ServerHandler{
     fn on_open_inner(&mut self, hs: Handshake) -> Result<()>{
-        if let Some(ip_addr) = hs.peer_addr {
-            let ip_string = format!("{}", ip_addr);
+        if let Some(ip_string) = client_addr(&hs){
             info!("{:>20} - connection {:?} established", ip_string, self.out.token());
             self.addr = ip_string;
         }
@@ -251,7 +340,7 @@ impl ServerHandler{
         }
 
         self.out.send(
-            serde_json::to_string(
+            self.codec.encode(
                 &self.store.list())?
             )?;
 
@@ -260,8 +349,8 @@ impl ServerHandler{
     fn on_message_inner(&mut self, msg: Message) -> Result<()> {
         use self::ClientState::*;
         use graph::Command::{FrontendAttach, BackendAttach};
-        let command = decode_command(msg)?;
-        let response = match self.state.clone() {
+        let Envelope{ req, command } = decode_command(self.codec, msg)?;
+        let response = match self.state {
             Frontend(client) =>
                 client.on_command(&self.out, &self.store, &command)?,
             Backend(client) => // different type for client than the above match
@@ -269,27 +358,52 @@ impl ServerHandler{
             AwaitingType => {
                 let out   = &self.out;
                 let store = &self.store;
-                let state = match command{
-                    FrontendAttach{ id } =>
-                        Frontend(FrontendClient::on_open(out, store, id)?),
-                    BackendAttach { id } => {
-                        let id = match id{
+                let codec = match command{
+                    FrontendAttach{ codec: Some(codec), .. } |
+                    BackendAttach { codec: Some(codec), .. } => codec,
+                    _ => self.codec
+                };
+                let acks = match command{
+                    FrontendAttach{ acks, .. } |
+                    BackendAttach { acks, .. } => acks,
+                    _ => false
+                };
+                let id = match command{
+                    // stay unattached so that the client can try again
+                    FrontendAttach{ id, .. } if !store.contains_key(id) =>
+                        return self.reply(req, Response::Error(DataValue::from(
+                            format!("GraphId {} does not exist", id)))),
+                    FrontendAttach{ id, .. } => id,
+                    BackendAttach { id, .. } =>
+                        match id{
                             Some(id) if self.store.contains_key(id) => id,
                             Some(id) => self.store.empty_at(id),
                             None     => self.store.new_empty()
-                        };
-                        Backend(BackendClient::on_open(out, store, id)?)
-                    },
+                        },
                     _ => 
                       return Err(WsError::new(WsErrorKind::Protocol,
                                          "Expected FrontendAttach or BackendAttach, got something else").into())
                 };
+                // sent before attaching so that it arrives ahead of every update
+                out.send(encode_response(codec, Reply{ req, response: Response::Attached{ id } })?)?;
+                let state = match command{
+                    FrontendAttach{ since, .. } =>
+                        Frontend(FrontendClient::on_open(out, store, id, codec, since)?),
+                    _ =>
+                        Backend(BackendClient::on_open(out, store, id, codec)?)
+                };
                 self.state = state;
-                Response::Ok
+                self.codec = codec;
+                self.acks  = acks;
+                return Ok(());
             }
         };
-        if response != Response::Ok{ // don't generate Ok messages, they're pointless and hard to coordinate
-            self.out.send(encode_response(response)?)?
+        self.reply(req, response)
+    }
+    fn reply(&self, req: Option<RequestId>, response: Response) -> Result<()>{
+        // Ok messages are only sent to clients that asked for them
+        if response != Response::Ok || self.acks{
+            self.out.send(encode_response(self.codec, Reply{ req, response })?)?
         }
         Ok(())
     }
@@ -303,7 +417,14 @@ impl Handler for ServerHandler{
     fn on_request(&mut self, req: &Request) -> WsResult<WsResponse> {
         let mut res = WsResponse::from_request(req)?;
 
-        let protocol_name = "selenologist-node-editor";
+        let protocol_name =
+            if req.protocols()?.contains(&MSGPACK_PROTOCOL_NAME){
+                self.codec = Codec::MsgPack;
+                MSGPACK_PROTOCOL_NAME
+            }
+            else{
+                PROTOCOL_NAME
+            };
         res.set_protocol(protocol_name);
 
         Ok(res)
@@ -329,7 +450,8 @@ impl Handler for ServerHandler{
 
 #[derive(Default)]
 struct ServerFactory{
-    store: GraphStore,
+    store:       GraphStore,
+    connections: Connections
 }
 
 impl Factory for ServerFactory{
@@ -340,20 +462,113 @@ impl Factory for ServerFactory{
             out,
             store: self.store.clone(),
             state: ClientState::AwaitingType,
-            addr:  "0.0.0.0:0".into()
+            codec: Codec::default(),
+            acks:  false,
+            addr:  "0.0.0.0:0".into(),
+            _open: self.connections.open()
         }
     }
 }
 
-pub fn launch_thread()
-    -> JoinHandle<()>
+fn io_error(e: WsError) -> io::Error{
+    match e.kind{
+        WsErrorKind::Io(e) => e,
+        _                  => io::Error::other(e.to_string())
+    }
+}
+
+/// The address a connection comes from. Connections through the HTTP port arrive from this host
+/// with the real address in X-Forwarded-For, which is ignored on connections from anywhere else
+/// since any client could send one.
+pub fn client_addr(hs: &Handshake) -> Option<String>{
+    let peer = hs.peer_addr?;
+    let from_this_host = peer.ip().is_loopback() || hs.local_addr.map(|local| local.ip()) == Some(peer.ip());
+    let forwarded = hs.request
+        .header("X-Forwarded-For")
+        .and_then(|value| ::std::str::from_utf8(value).ok())
+        .and_then(|value| value.split(',').next())
+        .map(|ip| ip.trim().to_string());
+    match forwarded{
+        Some(ip) if from_this_host => Some(ip),
+        _                          => Some(peer.ip().to_string())
+    }
+}
+
+/// Builds a websocket server for `factory` bound to `listen_addr`, along with the address
+/// actually bound.
+pub fn bind<F: Factory>(factory: F, listen_addr: SocketAddr)
+    -> io::Result<(WebSocket<F>, SocketAddr)>
 {
-    thread::Builder::new()
+    let server = WebSocket::new(factory)
+        .map_err(io_error)?
+        .bind(listen_addr)
+        .map_err(io_error)?;
+    let addr = server.local_addr()?;
+    Ok((server, addr))
+}
+
+/// Waits for a thread launched to serve on some address to report what it bound.
+pub fn bound<T>(bound_rx: Receiver<io::Result<T>>) -> io::Result<T>{
+    bound_rx.recv()
+        .unwrap_or_else(|_| Err(io::Error::other("the thread stopped before binding")))
+}
+
+/// Serves the graphs in `store`, which may be shared with other threads. Calling shutdown on the
+/// returned Sender stops the server. Also returns the address actually bound, which differs from
+/// `listen_addr` if its port is 0. Open connections are counted in `connections`.
+pub fn launch_thread(listen_addr: SocketAddr, store: GraphStore, connections: Connections)
+    -> io::Result<(JoinHandle<()>, Sender, SocketAddr)>
+{
+    let (bound_tx, bound_rx) = channel();
+    let handle = thread::Builder::new()
         .name("websocket".into())
         .spawn(move || {
-        let mut factory = ServerFactory::default();
-        let listen_addr = "127.0.0.1:3001";
-        info!("Attempting to listen on {}", listen_addr);
-        listen(listen_addr, |out| factory.connection_made(out)).unwrap()
-    }).unwrap()
+        let factory = ServerFactory{ store, connections };
+        let (server, addr) = match bind(factory, listen_addr){
+            Ok(bound) => bound,
+            Err(e)    => {
+                let _ = bound_tx.send(Err(e));
+                return;
+            }
+        };
+        let _ = bound_tx.send(Ok((server.broadcaster(), addr)));
+        info!("Listening on {}", addr);
+        server.run().unwrap();
+        info!("Stopped listening on {}", addr);
+    })?;
+    let (broadcaster, addr) = bound(bound_rx)?;
+    Ok((handle, broadcaster, addr))
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+
+    fn handshake(forwarded: Option<&str>, peer: &str) -> Handshake{
+        let mut head = String::from("GET / HTTP/1.1\r\n\
+                                     Connection: Upgrade\r\n\
+                                     Upgrade: websocket\r\n\
+                                     Sec-WebSocket-Version: 13\r\n\
+                                     Sec-WebSocket-Key: q16eN37NCfVwUChPvBdk4g==\r\n");
+        if let Some(forwarded) = forwarded{
+            head.push_str(&format!("X-Forwarded-For: {}\r\n", forwarded));
+        }
+        head.push_str("\r\n");
+        let request = Request::parse(head.as_bytes()).unwrap().unwrap();
+        Handshake{
+            response:   WsResponse::from_request(&request).unwrap(),
+            request,
+            peer_addr:  Some(peer.parse().unwrap()),
+            local_addr: Some("10.0.0.1:3001".parse().unwrap())
+        }
+    }
+
+    #[test]
+    fn forwarded_addresses_are_only_believed_from_this_host(){
+        let addr = |forwarded, peer| client_addr(&handshake(forwarded, peer));
+        assert_eq!(addr(Some("203.0.113.7"), "127.0.0.1:40000"), Some("203.0.113.7".into()));
+        assert_eq!(addr(Some("203.0.113.7, 10.1.1.1"), "10.0.0.1:40000"), Some("203.0.113.7".into()));
+        assert_eq!(addr(Some("203.0.113.7"), "198.51.100.2:40000"), Some("198.51.100.2".into()));
+        assert_eq!(addr(None, "127.0.0.1:40000"), Some("127.0.0.1".into()));
+    }
 }
@@ -0,0 +1,304 @@
+// Messages that put a field next to an enum, the way #[serde(flatten)] would. flatten serializes
+// maps without a length, which MessagePack can't encode, so these write the field into the map
+// the enum is written as and take it back out of the map when reading.
+
+use std::fmt;
+
+use serde::{Serialize, Serializer, Deserialize, Deserializer};
+use serde::ser::{self, Impossible, SerializeStruct, SerializeMap};
+use serde::de::{self, Visitor, MapAccess, EnumAccess, VariantAccess, DeserializeSeed, IntoDeserializer};
+use serde::forward_to_deserialize_any;
+
+/// Serializes `inner`, which has to serialize as a struct (as internally tagged enums do) or a
+/// newtype variant (as externally tagged ones do), with `field` first in it if `value` is Some.
+pub fn serialize_with<S, T, F>(serializer: S, inner: &T, field: &'static str, value: &Option<F>)
+    -> Result<S::Ok, S::Error>
+    where S: Serializer, T: Serialize, F: Serialize
+{
+    inner.serialize(WithField{ serializer, field, value })
+}
+
+/// Deserializes `T` from a map, taking `field` out of the map first. Externally tagged enums may
+/// only have newtype and unit variants.
+pub fn deserialize_with<'de, D, T, F>(deserializer: D, field: &'static str)
+    -> Result<(T, Option<F>), D::Error>
+    where D: Deserializer<'de>, T: Deserialize<'de>, F: Deserialize<'de>
+{
+    let mut value = None;
+    let inner = T::deserialize(WithoutField{ deserializer, field, value: &mut value })?;
+    Ok((inner, value))
+}
+
+struct WithField<'a, S, F: 'a>{
+    serializer: S,
+    field:      &'static str,
+    value:      &'a Option<F>
+}
+
+fn not_a_struct<E: ser::Error>() -> E{
+    E::custom("only structs and newtype variants can have a field added")
+}
+
+macro_rules! not_a_struct{
+    ($($method:ident($($arg:ty),*) -> $ret:ident;)*) => {$(
+        fn $method(self, $(_: $arg),*) -> Result<Self::$ret, S::Error>{
+            Err(not_a_struct())
+        }
+    )*}
+}
+
+impl<'a, S: Serializer, F: Serialize> Serializer for WithField<'a, S, F>{
+    type Ok                     = S::Ok;
+    type Error                  = S::Error;
+    type SerializeSeq           = Impossible<S::Ok, S::Error>;
+    type SerializeTuple         = Impossible<S::Ok, S::Error>;
+    type SerializeTupleStruct   = Impossible<S::Ok, S::Error>;
+    type SerializeTupleVariant  = Impossible<S::Ok, S::Error>;
+    type SerializeMap           = Impossible<S::Ok, S::Error>;
+    type SerializeStruct        = S::SerializeStruct;
+    type SerializeStructVariant = Impossible<S::Ok, S::Error>;
+
+    fn serialize_struct(self, name: &'static str, len: usize) -> Result<S::SerializeStruct, S::Error>{
+        match *self.value{
+            Some(ref value) => {
+                let mut state = self.serializer.serialize_struct(name, len + 1)?;
+                state.serialize_field(self.field, value)?;
+                Ok(state)
+            },
+            None => self.serializer.serialize_struct(name, len)
+        }
+    }
+
+    not_a_struct!{
+        serialize_bool(bool) -> Ok;
+        serialize_i8(i8) -> Ok;
+        serialize_i16(i16) -> Ok;
+        serialize_i32(i32) -> Ok;
+        serialize_i64(i64) -> Ok;
+        serialize_u8(u8) -> Ok;
+        serialize_u16(u16) -> Ok;
+        serialize_u32(u32) -> Ok;
+        serialize_u64(u64) -> Ok;
+        serialize_f32(f32) -> Ok;
+        serialize_f64(f64) -> Ok;
+        serialize_char(char) -> Ok;
+        serialize_str(&str) -> Ok;
+        serialize_bytes(&[u8]) -> Ok;
+        serialize_none() -> Ok;
+        serialize_unit() -> Ok;
+        serialize_unit_struct(&'static str) -> Ok;
+        serialize_unit_variant(&'static str, u32, &'static str) -> Ok;
+        serialize_seq(Option<usize>) -> SerializeSeq;
+        serialize_tuple(usize) -> SerializeTuple;
+        serialize_tuple_struct(&'static str, usize) -> SerializeTupleStruct;
+        serialize_tuple_variant(&'static str, u32, &'static str, usize) -> SerializeTupleVariant;
+        serialize_map(Option<usize>) -> SerializeMap;
+        serialize_struct_variant(&'static str, u32, &'static str, usize) -> SerializeStructVariant;
+    }
+
+    fn serialize_some<T: ?Sized + Serialize>(self, _: &T) -> Result<S::Ok, S::Error>{
+        Err(not_a_struct())
+    }
+    fn serialize_newtype_struct<T: ?Sized + Serialize>(self, _: &'static str, _: &T)
+        -> Result<S::Ok, S::Error>
+    {
+        Err(not_a_struct())
+    }
+    fn serialize_newtype_variant<T: ?Sized + Serialize>(self, _: &'static str, index: u32, variant: &'static str,
+                                                        inner: &T) -> Result<S::Ok, S::Error>
+    {
+        // the variant is keyed the way rmp_serde and serde_json key it, by index or by name
+        let by_name = self.serializer.is_human_readable();
+        let mut map = self.serializer.serialize_map(Some(if self.value.is_some(){ 2 } else { 1 }))?;
+        if let Some(ref value) = *self.value{
+            map.serialize_entry(self.field, value)?;
+        }
+        if by_name{
+            map.serialize_entry(variant, inner)?;
+        }
+        else{
+            map.serialize_entry(&index, inner)?;
+        }
+        map.end()
+    }
+}
+
+struct WithoutField<'a, D, F: 'a>{
+    deserializer: D,
+    field:        &'static str,
+    value:        &'a mut Option<F>
+}
+
+impl<'de, 'a, D: Deserializer<'de>, F: Deserialize<'de>> Deserializer<'de> for WithoutField<'a, D, F>{
+    type Error = D::Error;
+
+    fn deserialize_any<W: Visitor<'de>>(self, visitor: W) -> Result<W::Value, D::Error>{
+        self.deserializer.deserialize_any(FieldVisitor{ visitor, field: self.field, value: self.value })
+    }
+
+    // externally tagged, so the variant is one of the keys
+    fn deserialize_enum<W: Visitor<'de>>(self, _: &'static str, _: &'static [&'static str], visitor: W)
+        -> Result<W::Value, D::Error>
+    {
+        self.deserializer.deserialize_map(EnumVisitor{ visitor, field: self.field, value: self.value })
+    }
+
+    fn is_human_readable(&self) -> bool{
+        self.deserializer.is_human_readable()
+    }
+
+    forward_to_deserialize_any!{
+        bool i8 i16 i32 i64 i128 u8 u16 u32 u64 u128 f32 f64 char str string bytes byte_buf
+        option unit unit_struct newtype_struct seq tuple tuple_struct map struct identifier
+        ignored_any
+    }
+}
+
+struct FieldVisitor<'a, W, F: 'a>{
+    visitor: W,
+    field:   &'static str,
+    value:   &'a mut Option<F>
+}
+
+impl<'de, 'a, W: Visitor<'de>, F: Deserialize<'de>> Visitor<'de> for FieldVisitor<'a, W, F>{
+    type Value = W::Value;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result{
+        self.visitor.expecting(f)
+    }
+
+    fn visit_map<A: MapAccess<'de>>(self, map: A) -> Result<W::Value, A::Error>{
+        self.visitor.visit_map(FieldMap{ map, field: self.field, value: self.value })
+    }
+}
+
+struct FieldMap<'a, A, F: 'a>{
+    map:   A,
+    field: &'static str,
+    value: &'a mut Option<F>
+}
+
+impl<'de, 'a, A: MapAccess<'de>, F: Deserialize<'de>> MapAccess<'de> for FieldMap<'a, A, F>{
+    type Error = A::Error;
+
+    fn next_key_seed<K: DeserializeSeed<'de>>(&mut self, seed: K) -> Result<Option<K::Value>, A::Error>{
+        while let Some(key) = self.map.next_key::<String>()?{
+            if key != self.field{
+                let key: de::value::StringDeserializer<A::Error> = key.into_deserializer();
+                return seed.deserialize(key).map(Some);
+            }
+            *self.value = self.map.next_value()?;
+        }
+        Ok(None)
+    }
+
+    fn next_value_seed<T: DeserializeSeed<'de>>(&mut self, seed: T) -> Result<T::Value, A::Error>{
+        self.map.next_value_seed(seed)
+    }
+}
+
+struct EnumVisitor<'a, W, F: 'a>{
+    visitor: W,
+    field:   &'static str,
+    value:   &'a mut Option<F>
+}
+
+impl<'de, 'a, W: Visitor<'de>, F: Deserialize<'de>> Visitor<'de> for EnumVisitor<'a, W, F>{
+    type Value = W::Value;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result{
+        self.visitor.expecting(f)
+    }
+
+    fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<W::Value, A::Error>{
+        let EnumVisitor{ visitor, field, value } = self;
+        let mut visitor = Some(visitor);
+        let mut result  = None;
+        while let Some(key) = map.next_key::<Key>()?{
+            if key.is(field){
+                *value = map.next_value()?;
+                continue;
+            }
+            match visitor.take(){
+                Some(visitor) => result = Some(visitor.visit_enum(Entry{ variant: key, map: &mut map })?),
+                None          => return Err(de::Error::custom(format!("a second variant, {:?}", key)))
+            }
+        }
+        result.ok_or_else(|| de::Error::custom("no variant"))
+    }
+}
+
+// MessagePack writes variants as their index, JSON as their name
+#[derive(Debug)]
+enum Key{
+    Name(String),
+    Index(u64)
+}
+
+impl Key{
+    fn is(&self, field: &str) -> bool{
+        match *self{
+            Key::Name(ref name) => name == field,
+            Key::Index(_)       => false
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for Key{
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Key, D::Error>{
+        struct KeyVisitor;
+        impl<'de> Visitor<'de> for KeyVisitor{
+            type Value = Key;
+
+            fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result{
+                f.write_str("a field name or variant index")
+            }
+            fn visit_str<E: de::Error>(self, name: &str) -> Result<Key, E>{
+                Ok(Key::Name(name.into()))
+            }
+            fn visit_u64<E: de::Error>(self, index: u64) -> Result<Key, E>{
+                Ok(Key::Index(index))
+            }
+        }
+        deserializer.deserialize_any(KeyVisitor)
+    }
+}
+
+// the variant's key, with the map positioned at its value
+struct Entry<'m, A: 'm>{
+    variant: Key,
+    map:     &'m mut A
+}
+
+impl<'de, 'm, A: MapAccess<'de>> EnumAccess<'de> for Entry<'m, A>{
+    type Error   = A::Error;
+    type Variant = Self;
+
+    fn variant_seed<K: DeserializeSeed<'de>>(self, seed: K) -> Result<(K::Value, Self), A::Error>{
+        let variant = match self.variant{
+            Key::Name(ref name) => seed.deserialize(IntoDeserializer::<A::Error>::into_deserializer(name.as_str()))?,
+            Key::Index(index)   => seed.deserialize(IntoDeserializer::<A::Error>::into_deserializer(index))?
+        };
+        Ok((variant, self))
+    }
+}
+
+impl<'de, 'm, A: MapAccess<'de>> VariantAccess<'de> for Entry<'m, A>{
+    type Error = A::Error;
+
+    fn unit_variant(self) -> Result<(), A::Error>{
+        self.map.next_value()
+    }
+
+    fn newtype_variant_seed<T: DeserializeSeed<'de>>(self, seed: T) -> Result<T::Value, A::Error>{
+        self.map.next_value_seed(seed)
+    }
+
+    fn tuple_variant<W: Visitor<'de>>(self, _: usize, _: W) -> Result<W::Value, A::Error>{
+        Err(de::Error::custom("tuple variants can't have a field added"))
+    }
+
+    fn struct_variant<W: Visitor<'de>>(self, _: &'static [&'static str], _: W) -> Result<W::Value, A::Error>{
+        Err(de::Error::custom("struct variants can't have a field added"))
+    }
+}
@@ -0,0 +1,285 @@
+// Runs a whole Server on ports the OS picks and talks to it the way clients do.
+
+extern crate nodeeditor;
+extern crate rmp_serde;
+extern crate serde_json;
+extern crate url;
+extern crate ws;
+
+use nodeeditor::{Builder, Server, GRAPH_PATH};
+use nodeeditor::graph::*;
+use serde_json::Value;
+use url::Url;
+use ws::{Handler, Message, CloseCode, Request};
+
+use std::collections::BTreeMap;
+use std::env;
+use std::fs;
+use std::io::{Read, Write};
+use std::net::{SocketAddr, TcpStream};
+use std::process;
+use std::sync::Arc;
+use std::sync::mpsc::{channel, Sender, Receiver};
+use std::thread;
+use std::thread::JoinHandle;
+use std::time::Duration;
+
+fn start(name: &str) -> Server{
+    let root = env::temp_dir().join(format!("nodeeditor-it-{}-{}", name, process::id()));
+    fs::create_dir_all(&root).unwrap();
+    let any_port: SocketAddr = "127.0.0.1:0".parse().unwrap();
+    Builder::new()
+        .root(root)
+        .http_addr(any_port)
+        .ws_addr(any_port)
+        .reload_addr(any_port)
+        .start()
+        .unwrap()
+}
+
+// a -> b, both Labelled nodes with one port each
+fn two_nodes() -> GraphData{
+    let mut graph = GraphData::default();
+    for (id, port, node) in [("a", "a_out", Node::OutPort(PortSpec::default())),
+                             ("b", "b_in",  Node::InPort(PortSpec::default()))]{
+        let mut nodes = BTreeMap::new();
+        nodes.insert(port.to_string(), node);
+        graph.nodes.insert(id.into(), Node::Labelled{ data: id.into(), nodes });
+    }
+    graph
+}
+
+/// Sends one request over its own connection, returning the status and the body.
+fn http(addr: SocketAddr, method: &str, path: &str, body: &str) -> (u16, String){
+    let mut stream = TcpStream::connect(addr).unwrap();
+    write!(stream, "{} {} HTTP/1.1\r\nHost: {}\r\nConnection: close\r\n\
+                    Content-Type: application/json\r\nContent-Length: {}\r\n\r\n{}",
+           method, path, addr, body.len(), body).unwrap();
+    let mut response = String::new();
+    stream.read_to_string(&mut response).unwrap();
+    let status = response[9..12].parse().unwrap();
+    let body   = response.split("\r\n\r\n").nth(1).unwrap_or("").to_string();
+    (status, body)
+}
+
+struct Recorder{
+    tx:       Sender<Message>,
+    protocol: Option<&'static str>
+}
+
+impl Handler for Recorder{
+    fn build_request(&mut self, url: &Url) -> ws::Result<Request>{
+        let mut req = Request::from_url(url)?;
+        if let Some(protocol) = self.protocol{
+            req.add_protocol(protocol);
+        }
+        Ok(req)
+    }
+
+    fn on_message(&mut self, msg: Message) -> ws::Result<()>{
+        let _ = self.tx.send(msg);
+        Ok(())
+    }
+}
+
+/// A websocket client that keeps every message it gets.
+struct Client{
+    out:    ws::Sender,
+    rx:     Receiver<Message>,
+    thread: JoinHandle<()>
+}
+
+impl Client{
+    fn connect(url: String, protocol: Option<&'static str>) -> Client{
+        let (tx, rx)         = channel();
+        let (out_tx, out_rx) = channel();
+        let thread = thread::spawn(move ||
+            ws::connect(url, |out|{
+                out_tx.send(out).unwrap();
+                Recorder{ tx: tx.clone(), protocol }
+            }).unwrap());
+        Client{ out: out_rx.recv().unwrap(), rx, thread }
+    }
+
+    fn send(&self, codec: Codec, req: Option<RequestId>, command: Command){
+        self.out.send(codec.encode(&Envelope{ req, command }).unwrap()).unwrap();
+    }
+
+    fn next(&self) -> Message{
+        self.rx.recv_timeout(Duration::from_secs(5)).expect("no message arrived")
+    }
+
+    fn json(&self) -> Value{
+        match self.next(){
+            Message::Text(text) => serde_json::from_str(&text).unwrap(),
+            other => panic!("expected JSON, got {:?}", other)
+        }
+    }
+
+    fn nothing_more(&self){
+        if let Ok(msg) = self.rx.recv_timeout(Duration::from_millis(200)){
+            panic!("unexpected {:?}", msg);
+        }
+    }
+
+    fn close(self){
+        self.out.close(CloseCode::Normal).unwrap();
+        self.thread.join().unwrap();
+    }
+}
+
+// attaches a JSON frontend to `id`, returning it along with the seq its graph is at
+fn frontend(server: &Server, id: GraphId, since: Option<Seq>) -> (Client, Value){
+    let client = Client::connect(format!("ws://{}", server.ws_addr()), None);
+    assert!(client.json()["list"].is_array());
+    client.send(Codec::Json, None, Command::FrontendAttach{ id, codec: None, acks: false, since });
+    assert_eq!(client.json()["Response"]["_"], "Attached");
+    let first = client.json();
+    (client, first)
+}
+
+#[test]
+fn rest_endpoints(){
+    let server = start("rest");
+    let addr   = server.http_addr();
+    let graph  = serde_json::to_string(&two_nodes()).unwrap();
+    let link   = r#"{"source": "a_out", "target": "b_in"}"#;
+
+    assert_eq!(http(addr, "GET", "/api/graphs", ""), (200, r#"{"list":[]}"#.into()));
+    assert_eq!(http(addr, "GET", "/api/graphs/5", "").0, 404);
+    assert_eq!(http(addr, "PUT", "/api/graphs/5", &graph).0, 201);
+    assert_eq!(http(addr, "PUT", "/api/graphs/5", &graph).0, 200);
+    assert_eq!(http(addr, "PUT", "/api/graphs/5", "{").0, 400);
+    assert_eq!(http(addr, "GET", "/api/graphs", ""), (200, r#"{"list":[5]}"#.into()));
+
+    assert_eq!(http(addr, "POST", "/api/graphs/5/links", link).0, 200);
+    let (status, body) = http(addr, "GET", "/api/graphs/5", "");
+    assert_eq!(status, 200);
+    let saved: GraphData = serde_json::from_str(&body).unwrap();
+    assert!(saved.has_link(&"a_out".into(), &"b_in".into()));
+    assert_eq!(http(addr, "POST", "/api/graphs/5/links", r#"{"source": "a_out", "target": "nowhere"}"#).0, 422);
+    assert_eq!(http(addr, "DELETE", "/api/graphs/5/links", link).0, 200);
+    assert_eq!(http(addr, "POST", "/api/graphs/6/links", link).0, 404);
+    assert_eq!(http(addr, "PATCH", "/api/graphs/5", "{}").0, 405);
+
+    server.shutdown().unwrap();
+}
+
+#[test]
+fn websockets_are_reachable_through_the_http_port(){
+    let server = start("upgrade");
+    assert_eq!(http(server.http_addr(), "PUT", "/api/graphs/1", &serde_json::to_string(&two_nodes()).unwrap()).0, 201);
+    let client = Client::connect(format!("ws://{}{}", server.http_addr(), GRAPH_PATH), None);
+    assert_eq!(client.json(), serde_json::json!({ "list": [1] }));
+    client.send(Codec::Json, None, Command::FrontendAttach{ id: 1, codec: None, acks: false, since: None });
+    assert_eq!(client.json()["Response"]["id"], 1);
+    assert_eq!(client.json()["Command"]["_"], "SetGraph");
+    client.close();
+    server.shutdown().unwrap();
+}
+
+#[test]
+fn codecs_are_negotiated(){
+    let server = start("codecs");
+    let url    = format!("ws://{}", server.ws_addr());
+
+    // by subprotocol, for the whole connection
+    let client = Client::connect(url.clone(), Some(MSGPACK_PROTOCOL_NAME));
+    match client.next(){
+        Message::Binary(bytes) =>
+            assert_eq!(rmp_serde::from_slice::<GraphList>(&bytes).unwrap(), GraphList{ list: vec![] }),
+        other => panic!("expected MessagePack, got {:?}", other)
+    }
+    client.send(Codec::MsgPack, None, Command::BackendAttach{ id: Some(2), codec: None, acks: false });
+    match client.next(){
+        Message::Binary(bytes) =>
+            assert_eq!(rmp_serde::from_slice::<Update>(&bytes).unwrap(),
+                       Update::from(Reply{ req: None, response: Response::Attached{ id: 2 } })),
+        other => panic!("expected MessagePack, got {:?}", other)
+    }
+    client.close();
+
+    // by the attach, from then on
+    let client = Client::connect(url, Some(PROTOCOL_NAME));
+    assert!(client.json()["list"].is_array());
+    client.send(Codec::Json, None, Command::FrontendAttach{ id: 2, codec: Some(Codec::MsgPack), acks: false, since: None });
+    match (client.next(), client.next()){
+        (Message::Binary(attached), Message::Binary(graph)) => {
+            assert_eq!(rmp_serde::from_slice::<Update>(&attached).unwrap(),
+                       Update::from(Reply{ req: None, response: Response::Attached{ id: 2 } }));
+            let graph: Sequenced = rmp_serde::from_slice(&graph).unwrap();
+            assert_eq!(graph.update, Update::from(Command::SetGraph{ graph: Arc::default() }));
+        },
+        other => panic!("expected MessagePack, got {:?}", other)
+    }
+    client.close();
+    server.shutdown().unwrap();
+}
+
+#[test]
+fn acks_carry_request_ids(){
+    let server = start("acks");
+    assert_eq!(http(server.http_addr(), "PUT", "/api/graphs/1", &serde_json::to_string(&two_nodes()).unwrap()).0, 201);
+    let client = Client::connect(format!("ws://{}", server.ws_addr()), None);
+    client.json();
+    client.send(Codec::Json, Some(7), Command::FrontendAttach{ id: 1, codec: None, acks: true, since: None });
+    assert_eq!(client.json()["Response"], serde_json::json!({ "req": 7, "_": "Attached", "id": 1 }));
+    client.json(); // the graph
+
+    client.send(Codec::Json, Some(8), Command::AddLink{ source: "a_out".into(), target: "b_in".into() });
+    assert_eq!(client.json()["Command"]["_"], "AddLink");
+    assert_eq!(client.json()["Response"], serde_json::json!({ "req": 8, "_": "Ok" }));
+    client.send(Codec::Json, Some(9), Command::AddLink{ source: "a_out".into(), target: "nowhere".into() });
+    let error = client.json();
+    assert_eq!((&error["Response"]["req"], &error["Response"]["_"]), (&serde_json::json!(9), &serde_json::json!("Err")));
+    client.send(Codec::Json, None, Command::Undo);
+    assert_eq!(client.json()["Command"]["_"], "DelLink");
+    assert_eq!(client.json()["Response"], serde_json::json!({ "_": "Ok" }));
+    client.nothing_more();
+
+    client.close();
+    server.shutdown().unwrap();
+}
+
+#[test]
+fn reconnecting_frontends_only_get_what_they_missed(){
+    let server = start("replay");
+    let addr   = server.http_addr();
+    assert_eq!(http(addr, "PUT", "/api/graphs/1", &serde_json::to_string(&two_nodes()).unwrap()).0, 201);
+
+    let (client, first) = frontend(&server, 1, None);
+    assert_eq!(first["Command"]["_"], "SetGraph");
+    let seen = first["seq"].as_u64().unwrap();
+    client.close();
+
+    // edits from the API reach frontends like a backend's
+    assert_eq!(http(addr, "POST", "/api/graphs/1/links", r#"{"source": "a_out", "target": "b_in"}"#).0, 200);
+    let (client, missed) = frontend(&server, 1, Some(seen));
+    assert_eq!(missed["seq"].as_u64(), Some(seen + 1));
+    assert_eq!(missed["Command"], serde_json::json!({ "_": "AddLink", "source": "a_out", "target": "b_in" }));
+    client.nothing_more();
+    client.close();
+
+    // a seq the server never handed out gets the whole graph
+    let (client, first) = frontend(&server, 1, Some(seen + 100));
+    assert_eq!(first["seq"].as_u64(), Some(seen + 1));
+    assert_eq!(first["Command"]["_"], "SetGraph");
+    let graph: Arc<GraphData> = serde_json::from_value(first["Command"]["graph"].clone()).unwrap();
+    assert!(graph.has_link(&"a_out".into(), &"b_in".into()));
+    client.close();
+
+    server.shutdown().unwrap();
+}
+
+#[test]
+fn shutting_down_closes_clients_and_ports(){
+    let server = start("shutdown");
+    let addr   = server.http_addr();
+    let client = Client::connect(format!("ws://{}{}", addr, GRAPH_PATH), None);
+    client.json();
+
+    server.shutdown().unwrap();
+    // the connection was closed from the server's end, so the client's thread is done
+    client.thread.join().unwrap();
+    assert!(TcpStream::connect(addr).is_err());
+}